@@ -5,15 +5,193 @@
 //! It's not meant to produce debug info or care about safety checks otherwise.
 //! Speed, Safety, Resource usage... pick 2.
 
-use core::{mem::size_of, ptr::null_mut};
+use core::{alloc::Layout, mem::size_of, ptr::null_mut};
+#[cfg(feature = "track")]
+use core::{fmt, panic::Location};
+
+#[cfg(unix)]
+use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+#[cfg(windows)]
+use core::ffi::c_void;
+use crate::arch::{CacheTopology, CpuFeatures};
+use super::block::{
+    block_size, BlockHeader, ChunkHeader, BLOCK_ALIGN, CHUNK_HEADER, HEADER, MIN_BLOCK,
+};
+use super::direct::DirectMaps;
+use super::fault::{FaultInjector, FaultPolicy};
+#[cfg(target_os = "linux")]
+use super::guard::{self, GuardMode};
+use super::memmap::MemoryMap;
+#[cfg(feature = "poison")]
+use super::poison::{self, BLOCK_LINK, SLAB_LINK};
+use super::slab::{class_of, class_size, SlabClasses, SLAB_SIZE};
+use super::source::{ChunkSource, OsChunks, StaticRegion};
+use super::stats::AllocStats;
+#[cfg(feature = "track")]
+use super::track::{self, Site, Tracker};
+
+// Smallest unit mmap hands out, anything aligned past this skips the chunks
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+/// Maps a zeroed region straight from the kernel, no libc malloc in the way.
+/// Returns null if the kernel says no.
+#[cfg(unix)]
+#[inline]
+pub(crate) unsafe fn map_chunk(size: usize) -> *mut u8 {
+    unsafe {
+        let ptr = mmap(
+            null_mut(),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ptr == MAP_FAILED {
+            null_mut()
+        } else {
+            ptr as *mut u8
+        }
+    }
+}
+
+/// Hands a region from `map_chunk` back to the kernel.
+#[cfg(unix)]
+#[inline]
+pub(crate) unsafe fn unmap_chunk(ptr: *mut u8, size: usize) {
+    unsafe {
+        munmap(ptr as *mut libc::c_void, size);
+    }
+}
+
+/// `map_chunk` aligned past a page. Over-maps by `align`, then gives back
+/// the slop on both ends so the result unmaps with exactly `size`.
+#[cfg(unix)]
+pub(crate) unsafe fn map_aligned(size: usize, align: usize) -> *mut u8 {
+    unsafe {
+        let slop = if align > PAGE_SIZE { align } else { 0 };
+        let base = map_chunk(size + slop);
+        if base.is_null() {
+            return null_mut(); // Allocation failed
+        }
+        let ptr = ((base as usize + align - 1) & !(align - 1)) as *mut u8;
+        if slop != 0 {
+            let head = ptr as usize - base as usize;
+            if head != 0 {
+                unmap_chunk(base, head);
+            }
+            if slop - head != 0 {
+                unmap_chunk(ptr.add(size), slop - head);
+            }
+        }
+        ptr
+    }
+}
+
+/// Gives back a `map_aligned` region, same `size` and `align` it was mapped with.
+#[cfg(unix)]
+#[inline]
+pub(crate) unsafe fn unmap_aligned(ptr: *mut u8, size: usize, _align: usize) {
+    unsafe { unmap_chunk(ptr, size) } // munmap doesn't care how it got lined up
+}
+
+// Only what the Windows paths need, `externs` isn't part of the build
+#[cfg(windows)]
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    pub(crate) fn VirtualAlloc(address: *mut c_void, size: usize, allocation_type: u32, protect: u32) -> *mut c_void;
+    pub(crate) fn VirtualFree(address: *mut c_void, size: usize, free_type: u32) -> i32;
+    pub(crate) fn GetProcessHeap() -> *mut c_void;
+    pub(crate) fn HeapAlloc(heap: *mut c_void, flags: u32, bytes: usize) -> *mut c_void;
+    pub(crate) fn HeapFree(heap: *mut c_void, flags: u32, mem: *mut c_void) -> i32;
+}
+
+#[cfg(windows)]
+pub(crate) const MEM_COMMIT: u32 = 0x1000;
+#[cfg(windows)]
+pub(crate) const MEM_RESERVE: u32 = 0x2000;
+#[cfg(windows)]
+pub(crate) const MEM_RELEASE: u32 = 0x8000;
+#[cfg(windows)]
+pub(crate) const PAGE_READWRITE: u32 = 0x04;
+#[cfg(windows)]
+const PAGE_NOACCESS: u32 = 0x01;
+
+// What VirtualAlloc lines everything up to anyway
+#[cfg(windows)]
+pub(crate) const GRANULARITY: usize = 64 << 10;
+
+// VirtualAlloc instead of mmap, still zeroed and still never the global
+// allocator, which may well be us
+#[cfg(windows)]
+#[inline]
+pub(crate) unsafe fn map_chunk(size: usize) -> *mut u8 {
+    unsafe { map_aligned(size, PAGE_SIZE) }
+}
+
+#[cfg(windows)]
+#[inline]
+pub(crate) unsafe fn unmap_chunk(ptr: *mut u8, size: usize) {
+    unsafe { unmap_aligned(ptr, size, PAGE_SIZE) }
+}
+
+/// Past 64KB there's no trimming a reservation, so reserve enough to line
+/// up, let it go and take the aligned part. Another thread can get there
+/// first in between, hence the retries.
+#[cfg(windows)]
+pub(crate) unsafe fn map_aligned(size: usize, align: usize) -> *mut u8 {
+    unsafe {
+        if align <= GRANULARITY {
+            return VirtualAlloc(null_mut(), size, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) as *mut u8;
+        }
+        let Some(padded) = size.checked_add(align) else {
+            return null_mut(); // Size overflows once aligned
+        };
+        for _ in 0..8 {
+            let probe = VirtualAlloc(null_mut(), padded, MEM_RESERVE, PAGE_NOACCESS);
+            if probe.is_null() {
+                return null_mut(); // Allocation failed
+            }
+            let ptr = (probe as usize + align - 1) & !(align - 1);
+            VirtualFree(probe, 0, MEM_RELEASE);
+            let got = VirtualAlloc(ptr as *mut c_void, size, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE);
+            if !got.is_null() {
+                return got as *mut u8;
+            }
+        }
+        null_mut()
+    }
+}
+
+#[cfg(windows)]
+#[inline]
+pub(crate) unsafe fn unmap_aligned(ptr: *mut u8, _size: usize, _align: usize) {
+    unsafe {
+        VirtualFree(ptr as *mut c_void, 0, MEM_RELEASE); // Release wants a size of 0
+    }
+}
 
-#[cfg(target_arch = "x86")]
-use core::arch::x86::{__cpuid, __cpuid_count};
-#[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::{__cpuid, __cpuid_count};
-use libc::{mmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
-use winapi::um::memoryapi::VirtualAlloc;
-use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE};
+// Nothing to map from. The global allocator would be the only thing left,
+// and that may be us, so it's null: `from_region` or a `ChunkSource` of your own
+#[cfg(not(any(unix, windows)))]
+#[inline]
+pub(crate) unsafe fn map_chunk(_size: usize) -> *mut u8 {
+    null_mut()
+}
+
+#[cfg(not(any(unix, windows)))]
+#[inline]
+pub(crate) unsafe fn unmap_chunk(_ptr: *mut u8, _size: usize) {}
+
+#[cfg(not(any(unix, windows)))]
+#[inline]
+pub(crate) unsafe fn map_aligned(_size: usize, _align: usize) -> *mut u8 {
+    null_mut()
+}
+
+#[cfg(not(any(unix, windows)))]
+#[inline]
+pub(crate) unsafe fn unmap_aligned(_ptr: *mut u8, _size: usize, _align: usize) {}
 
 // Macros for SIMD detection
 #[macro_export]
@@ -44,163 +222,6 @@ macro_rules! avx512_support {
     };
 }
 
-macro_rules! any_avx_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        any(target_feature = "avx", target_feature = "avx2"),
-                        not(target_feature = "avx512f")
-                )
-            )
-        )]
-        #[repr(C, align(32))] // AVX alignment (256)
-    };
-}
-
-macro_rules! avx2_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "avx2",
-                        not(target_feature = "avx512f")
-                )
-            )
-        )]
-        #[repr(C, align(32))] // AVX alignment
-    };
-}
-
-macro_rules! avx_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "avx",
-                        not(target_feature = "avx512f", target_feature = "avx2")
-                )
-            )
-        )]
-        #[repr(C, align(32))] // AVX alignment
-    };
-}
-
-macro_rules! any_sse_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",)
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-
-macro_rules! sse42_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse4.2",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",)
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-
-macro_rules! sse41_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse4.1",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",
-                            target_feature = "sse4.2",)
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-
-macro_rules! sse3_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse3",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",
-                            target_feature = "sse4.2",
-                            target_feature = "sse4.1",
-                            target_feature = "sse3",)
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-
-macro_rules! sse2_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse2",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",
-                            target_feature = "sse4.2",
-                            target_feature = "sse4.1",
-                            target_feature = "sse3",)
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-macro_rules! sse_support {
-    () => {
-        #[cfg(
-            all(
-                any(target_arch = "x86_64", target_arch = "x86"),
-                all(
-                        target_feature = "sse",
-                        not(target_feature = "avx512f",
-                            target_feature = "avx2",
-                            target_feature = "avx",
-                            target_feature = "sse4.2",
-                            target_feature = "sse4.1",
-                            target_feature = "sse3",
-                            target_feature = "sse2")
-                )
-            )
-        )]
-        #[repr(C, align(16))] // AVX alignment
-    };
-}
-
 #[macro_export]
 macro_rules! no_simd_support {
     () => {
@@ -222,60 +243,6 @@ macro_rules! no_simd_support {
     };
 }
 
-avx512_support!();
-#[derive(Copy, Clone)]
-pub struct CacheInfo {
-    // Hot 32-byte block (AVX-256 aligned)
-    pub cache_size: u32,    // +0  (4-byte aligned)
-    pub prefetch_size: u16, // +4  (2-byte aligned)
-    pub line_size: u8,      // +6
-    _padding1: u8,          // +7  (maintain 8-byte alignment)
-
-    // Cold 32-byte block
-    pub cache_sets: u16,   // +8  (2-byte aligned)
-    pub associativity: u8, // +10
-    pub cache_level: u8,   // +11
-    pub shared_cores: u8,  // +12
-    _padding2: [u8; 19],   // +13-31 (pad to 32 bytes)
-
-    // Ensure 64-byte total size
-    _padding3: [u8; 32], // +32-63 (pad to 64 bytes)
-}
-
-any_avx_support!();
-#[derive(Copy, Clone)]
-pub struct CacheInfo {
-    // Hot 16-byte block
-    pub cache_size: u32,    // +0  (4-byte aligned)
-    pub prefetch_size: u16, // +4  (2-byte aligned)
-    pub line_size: u8,      // +6
-    pub cache_level: u8,    // +7
-    pub cache_sets: u16,    // +8  (2-byte aligned)
-    pub associativity: u8,  // +10
-    pub shared_cores: u8,   // +11
-    _padding1: [u8; 4],     // +12-15 (align to 16)
-
-    // Cold 16-byte block
-    _padding2: [u8; 16], // +16-31 (pad to 32 bytes)
-}
-
-any_sse_support!();
-#[derive(Copy, Clone)]
-pub struct CacheInfo {
-    // Hot 8-byte block (First XMM half)
-    pub cache_size: u32,    // +0  (4-byte aligned)
-    pub prefetch_size: u16, // +4  (2-byte aligned)
-    pub line_size: u8,      // +6
-    pub cache_level: u8,    // +7
-
-    // Cold 8-byte block (Second XMM half)
-    pub cache_sets: u16,   // +8  (2-byte aligned)
-    pub associativity: u8, // +10
-    pub shared_cores: u8,  // +11
-    _padding: [u8; 4],     // +12-15 (align to 16)
-}
-
-no_simd_support!();
 #[repr(C, align(8))] // Basic 8-byte alignment
 #[derive(Copy, Clone)]
 pub struct CacheInfo {
@@ -297,440 +264,1411 @@ pub struct CacheInfo {
 impl CacheInfo {
     #[inline]
     pub fn new() -> Self {
-        Self.detect()
+        unsafe { Self::detect() }
     }
 
+    /// Sane L1 numbers for when we can't (or can't yet) ask the CPU.
+    /// Const, so an `Allocator` can live in a static.
     #[inline]
-    pub unsafe fn detect() -> Self {
-        any_simd_support!();
-        unsafe_or_explode! {
-            // Get CPU info
-            let cpu_info = __cpuid(1);
-            let cache = __cpuid(0x0000_0004);
-
-            // Feature detection
-            let has_avx512f = (cpu_info.ecx & (1 << 28)) != 0;
-            let has_avx2 = (cpu_info.ebx & (1 << 5)) != 0;
-            let has_avx = (cpu_info.ecx & (1 << 28)) != 0;
-            let has_sse4_2 = (cpu_info.ecx & (1 << 20)) != 0;
-            let has_sse4_1 = (cpu_info.ecx & (1 << 19)) != 0;
-            let has_ssse3 = (cpu_info.ecx & (1 << 9)) != 0;
-            let has_sse3 = (cpu_info.ecx & (1 << 0)) != 0;
-            let has_sse2 = (cpu_info.edx & (1 << 26)) != 0;
-            let has_sse = (cpu_info.edx & (1 << 25)) != 0;
-
-            // Cache info extraction
-            let line_size = (cache.eax & 0xff) as u8;
-            let cache_level = ((cache.eax >> 5) & 0x7) as u8;
-            let associativity = ((cache.ebx >> 22) & 0x3ff) as u8;
-            let shared_cores = ((cache.eax >> 14) & 0xfff) as u8;
-            let cache_sets = (cache.ecx + 1) as u16;
-
-            // Determine prefetch size based on available features
-            let prefetch_size = if has_avx512f {
-                (line_size as u16) * 8 // 512-bit operations
-            } else if has_avx2 || has_avx {
-                (line_size as u16) * 4 // 256-bit operations
-            } else if has_sse4_2 || has_sse4_1 {
-                (line_size as u16) * 2 // 128-bit operations with newer SSE
-            } else if has_ssse3 || has_sse3 || has_sse2 || has_sse {
-                line_size as u16 // Basic 128-bit operations
-            } else {
-                line_size as u16 // Fallback
-            };
-
-            Self {
-                line_size,
-                cache_level,
-                associativity,
-                prefetch_size,
-                cache_size: ((cache.ebx >> 22) & 0x3ff) *
-                ((cache.ebx >> 12) & 0x3ff) *
-                (line_size as u32) *
-                (cache_sets as u32),
-                cache_sets,
-                shared_cores,
-                _padding: [0; 4],
-            };
-        }
-
-        // Fallback for non-x86 architectures
-        no_simd_support!();
-        const fn fallback() -> Self {
-            Self {
-                line_size: 64, // Common default
-                cache_level: 1,
-                associativity: 8,
-                prefetch_size: 64,
-                cache_size: 32768, // 32KB default L1
-                cache_sets: 64,
-                shared_cores: 1,
-                _padding: [0; 4],
-            }
-        }
+    pub const fn fallback() -> Self {
+        // Every layout variant is plain integers, zero is a valid start
+        let mut info: Self = unsafe { core::mem::zeroed() };
+        info.line_size = 64; // Common default
+        info.cache_level = 1;
+        info.associativity = 8;
+        info.prefetch_size = 64;
+        info.cache_size = 32768; // 32KB default L1
+        info.cache_sets = 64;
+        info.shared_cores = 1;
+        info
     }
-}
 
-// Yummy MemoryMap slices
-#[repr(transparent)]
-pub struct MemoryMap {
-    data: [u8; u8],
-}
+    /// L1d from `CacheTopology`, prefetch scaled by the widest vector unit.
+    /// Falls back to `fallback()` numbers if the topology comes up empty.
+    #[inline]
+    pub unsafe fn detect() -> Self {
+        let mut info = Self::fallback();
+        if let Some(l1) = CacheTopology::detect().data(1) {
+            info.line_size = l1.line_size as u8;
+            info.cache_level = l1.level;
+            info.associativity = l1.ways.min(u8::MAX as u16) as u8;
+            info.cache_size = l1.size;
+            info.cache_sets = l1.sets.min(u16::MAX as u32) as u16;
+            info.shared_cores = l1.shared_cores.min(u8::MAX as u16) as u8;
+        }
 
-impl MemoryMap {
-    pub fn new() -> Self {
-        avx512_support!();
-        Self { data: [0; 64] }; // AVX-512: 512 bits
-        any_avx_support!();
-        Self { data: [0; 32] }; // AVX/AVX2: 256 bits
-        any_sse_support!();
-        Self { data: [0; 16] }; // SSE variants: 128 bits
-        no_simd_support!();
-        Self { data: [0; 8] }; // Basic: 64 bits
+        // Prefetch one vector register's worth of lines
+        let features = CpuFeatures::get();
+        let lines = if features.has(CpuFeatures::AVX512F) {
+            8 // 512-bit operations
+        } else if features.has(CpuFeatures::AVX2) || features.has(CpuFeatures::AVX) {
+            4 // 256-bit operations
+        } else if features.has(CpuFeatures::SSE4_1) || features.has(CpuFeatures::SSE4_2) {
+            2 // 128-bit operations with newer SSE
+        } else {
+            1 // Basic 128-bit operations, or nothing at all
+        };
+
+        info.prefetch_size = info.line_size as u16 * lines;
+        info
     }
 }
 
-pub struct Allocator {
+pub struct Allocator<S: ChunkSource = OsChunks> {
     // Current chunk - pointers must match architecture
     current_chunk: *mut u8,
     current_offset: u32, // 4GB per chunk is reasonable
 
     // Cache specifics - modern CPUs typically < 1MB L1
     cache_info: CacheInfo,
-    l1_cache_size: u16, // 64KB is common for L1
-    prefetch_size: u8,  // 256 bytes max is typical
 
-    // Adding a MemoryMap via slices, since I like pi, lol
-    mem_map: MemoryMap,
+    // Small requests never touch the bump pointer directly
+    slabs: SlabClasses,
 
     // Chunk management
+    source: S, // Where chunks come from, picked at construction
     chunk_size: u32, // 4GB max chunk
-    free_chunks: *mut u8,
-    largest_free_chunk: *mut u8,
+    chunks: *mut ChunkHeader, // Every chunk we own, newest first
+    last_block: *mut BlockHeader, // Right before the bump frontier, null if none
+    free_chunks: *mut BlockHeader, // Free blocks, coalesced on the way in
+    largest_free_chunk: *mut BlockHeader, // So a hopeless scan never starts
+    direct: DirectMaps, // Big ones mapped on their own, not in any chunk
 
     // Masks/alignments - architecture dependent
     align_mask: usize, // Must match pointer size
-    chunk_mask: usize, // Must match pointer size
 
     // Limits
-    max_bytes: usize,  // 4GB total is reasonable
+    max_bytes: usize,  // Hard limit, past it is OOM
+    soft_bytes: usize, // Soft limit, past it is pressure
     free_bytes: usize, // Track against max_bytes
     used_bytes: usize, // Track against max_bytes
+
+    // Who hears about the limits, both off by default
+    on_pressure: Option<PressureHandler<S>>,
+    on_oom: Option<OomHandler>,
+    in_pressure: bool, // on_pressure is running, it gets no second call
+
+    // Fake OOMs for tests, off by default
+    faults: FaultInjector,
+
+    // What the post-mortem gets to read
+    stats: AllocStats,
+
+    // Debug mode, every allocation gets fenced in by PROT_NONE pages
+    #[cfg(target_os = "linux")]
+    guard: Option<GuardMode>,
+
+    // Call sites of everything live, in its own mapping
+    #[cfg(feature = "track")]
+    tracker: Tracker,
 }
 
+/// Called when an allocation is about to cross the soft limit, with the
+/// allocator it's about to happen in. Free caches through it, then return.
+pub type PressureHandler<S = OsChunks> = fn(&mut Allocator<S>, Layout);
+
+/// Called with the request that didn't fit and the counters at that moment.
+/// Return and the allocation gives back null, or explode right here.
+pub type OomHandler = fn(Layout, &AllocStats);
+
+// Every pointer in here is into memory this allocator mapped and nobody else
+// holds, handlers are plain fns. Moving the lot to another thread is fine as
+// long as the source can go too.
+unsafe impl<S: ChunkSource + Send> Send for Allocator<S> {}
+
 impl Allocator {
     #[inline]
     pub const fn new() -> Self {
-        let cache_info = CacheInfo::detect();
+        Self::with_source(OsChunks)
+    }
+
+    /// Debug allocator, every allocation sits against a `PROT_NONE` page so
+    /// overruns (or underruns) fault on the exact access. Slow and hungry:
+    /// freed pages are never handed back, see `AllocStats::guard_bytes`.
+    /// Nothing keeps a list of the guarded mappings either, so `clear()` and
+    /// drop can't unmap what's still live. Fine for a debug run, not more.
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub const fn guarded(mode: GuardMode) -> Self {
+        let mut allocator = Self::new();
+        allocator.guard = Some(mode);
+        allocator
+    }
+}
+
+impl Default for Allocator {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allocator<StaticRegion> {
+    /// Everything comes out of `region`, no OS and no global allocator.
+    /// Chunks and slabs shrink to fit small regions. Under four pages no slab
+    /// fits, small requests take blocks instead, and `Tlsf` is the better fit.
+    pub fn from_region(region: &'static mut [u8]) -> Self {
+        // A page goes to lining the first chunk up
+        let chunk_size = region.len().saturating_sub(PAGE_SIZE) & !(PAGE_SIZE - 1);
+        let chunk_size = chunk_size.clamp(PAGE_SIZE, 1 << 20);
+        let mut allocator = Self::with_source(StaticRegion::new(region));
+        allocator.chunk_size = chunk_size as u32;
+        allocator
+    }
+}
+
+impl<S: ChunkSource> Allocator<S> {
+    /// Chunks come from `source`, for when neither mmap nor a static
+    /// region is the right answer.
+    #[inline]
+    pub const fn with_source(source: S) -> Self {
+        // Const so it can sit behind #[global_allocator], see detect_cache()
         Self {
             // Current chunk - pointers must match architecture
             current_chunk: null_mut(),
             current_offset: 0,
 
             // Cache specifics - modern CPUs typically < 1MB L1
-            cache_info,
-            l1_cache_size: (cache_info.l1_size >> 10) as u16, // Convert to KB
-            prefetch_size: cache_info.prefetch_size as u8,
+            cache_info: CacheInfo::fallback(),
 
-            // Adding a MemoryMap via slices, since I like pi, lol
-            mem_map: MemoryMap::new(),
+            // Small requests never touch the bump pointer directly
+            slabs: SlabClasses::new(),
 
             // Chunk management
+            source,
             chunk_size: 1 << 20, // 1MB chunks
+            chunks: null_mut(),
+            last_block: null_mut(),
             free_chunks: null_mut(),
             largest_free_chunk: null_mut(),
+            direct: DirectMaps::new(),
 
             // Masks/alignments - architecture dependent
-            align_mask: sizeof::<usize>() - 1,
-            chunk_mask: !((1 << 20) - 1), // Invert chunk size - 1
+            align_mask: size_of::<usize>() - 1,
 
             // Limits
             max_bytes: usize::MAX,
+            soft_bytes: usize::MAX,
             free_bytes: usize::MAX,
             used_bytes: 0,
+
+            // Who hears about the limits, both off by default
+            on_pressure: None,
+            on_oom: None,
+            in_pressure: false,
+
+            // Fake OOMs for tests, off by default
+            faults: FaultInjector::new(),
+
+            // What the post-mortem gets to read
+            stats: AllocStats::new(),
+
+            // Debug mode, every allocation gets fenced in by PROT_NONE pages
+            #[cfg(target_os = "linux")]
+            guard: None,
+
+            // Call sites of everything live, in its own mapping
+            #[cfg(feature = "track")]
+            tracker: Tracker::new(),
         }
     }
 
+    /// Swaps the const fallback cache numbers for what the CPU reports.
+    #[inline]
+    pub fn detect_cache(&mut self) {
+        self.cache_info = CacheInfo::new();
+    }
+
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    #[inline]
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    #[inline]
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    #[inline]
+    pub fn soft_limit(&self) -> usize {
+        self.soft_bytes
+    }
+
+    /// Past `soft` the pressure handler runs, past `hard` allocations fail
+    /// and the OOM handler runs. `usize::MAX` turns either one off.
+    #[inline]
+    pub fn set_limits(&mut self, soft: usize, hard: usize) {
+        self.soft_bytes = soft.min(hard);
+        self.max_bytes = hard;
+        self.free_bytes = hard.saturating_sub(self.used_bytes);
+    }
+
+    #[inline]
+    pub fn set_pressure_handler(&mut self, handler: Option<PressureHandler<S>>) {
+        self.on_pressure = handler;
+    }
+
+    #[inline]
+    pub fn set_oom_handler(&mut self, handler: Option<OomHandler>) {
+        self.on_oom = handler;
+    }
+
+    /// Makes allocations fail on purpose, see `FaultPolicy`. Failures go
+    /// through the OOM handler like real ones. Resets the policy's counters.
+    #[inline]
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) {
+        self.faults.set(policy);
+    }
+
+    #[inline]
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.faults.policy()
+    }
+
+    /// How many allocations were failed on purpose so far.
+    #[inline]
+    pub fn faults_injected(&self) -> u64 {
+        self.faults.injected()
+    }
+
+    /// Copy of the counters, take it before you explode.
+    #[inline]
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    /// Snapshot of which cache lines in our chunks are in use, see `MemoryMap`.
+    pub fn memory_map(&self) -> MemoryMap {
+        let line_size = match self.cache_info.line_size {
+            0 => 64, // Nothing detected, everybody's is 64 anyway
+            line_size => line_size as usize,
+        };
+        let frontier = self.current_chunk.wrapping_add(self.current_offset as usize);
+        unsafe { MemoryMap::build(self.chunks, frontier, line_size) }
+    }
+
+    #[inline(always)]
+    fn charge(&mut self, requested: usize, charged: usize, granted: usize) {
+        self.used_bytes += charged;
+        self.free_bytes = self.max_bytes.saturating_sub(self.used_bytes);
+        self.stats.record_alloc(requested, charged, granted);
+    }
+
+    #[inline(always)]
+    fn refund(&mut self, requested: usize, charged: usize, granted: usize) {
+        self.used_bytes -= charged;
+        self.free_bytes = self.max_bytes.saturating_sub(self.used_bytes);
+        self.stats.record_free(requested, charged, granted);
+    }
+
+    /// Every live tracked allocation, in no particular order.
+    #[cfg(feature = "track")]
+    #[inline]
+    pub fn live_sites(&self) -> &[Site] {
+        self.tracker.sites()
+    }
+
+    /// Writes every live allocation and its call site to `out`.
+    /// Writes nothing if there's nothing live.
+    #[cfg(feature = "track")]
+    pub fn write_leaks<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        track::write_report(&self.tracker, out)
+    }
+
+    /// `write_leaks` to stderr, returns how many allocations are still live.
+    #[cfg(feature = "track")]
+    pub fn report_leaks(&self) -> usize {
+        let _ = self.write_leaks(&mut track::Stderr);
+        self.tracker.sites().len()
+    }
+
+    /// Allocates `layout`, aligned to whatever it asks for.
+    /// Up to a page goes to the size classes, up to a quarter chunk is bumped,
+    /// and anything bigger or page-plus aligned gets its own mapping.
     // https://github.com/rust-lang/rust/blob/master/library/alloc/src/alloc.rs#L23
-    pub fn allocate(&mut self, size: usize) -> *mut u8 {
-        if size > self.max_bytes - self.used_bytes {
-            return null_mut(); // Out of memory
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if !self.admit(layout, layout.size()) {
+            return null_mut();
         }
+        self.allocate_admitted(layout)
+    }
 
-        // Align size to cache line and chunk boundaries
-        let aligned_size = (size + self.align_mask) & !self.align_mask;
-        let aligned_chunk = (aligned_size + self.chunk_mask) & !self.chunk_mask;
-
-        // Check if current chunk is sufficient
-        if self.current_offset + aligned_chunk > self.chunk_size {
-            // Allocate new chunk if needed
-            self.current_chunk = unsafe {
-                core::alloc::alloc_zeroed(
-                    core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-                )
-            };
-            if self.current_chunk.is_null() {
-                return null_mut(); // Allocation failed
-            }
-            self.current_offset = 0;
+    // Soft limit and fault injection for `growth` more bytes on behalf of
+    // `layout`, false means the request gets null
+    #[inline(always)]
+    fn admit(&mut self, layout: Layout, growth: usize) -> bool {
+        if self.used_bytes <= self.soft_bytes && growth > self.soft_bytes - self.used_bytes {
+            self.relieve_pressure(layout);
+        }
+        if self.faults.should_fail(growth) {
+            self.out_of_memory(layout);
+            return false;
         }
+        true
+    }
 
-        // Allocate memory from current chunkcan
-        let ptr = unsafe { self.current_chunk.add(self.current_offset as usize) };
-        self.current_offset += aligned_chunk as u32;
-        self.used_bytes += aligned_size;
+    #[inline(always)]
+    #[cfg_attr(feature = "track", track_caller)]
+    fn allocate_admitted(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocate_untracked(layout.size(), layout.align());
+        if ptr.is_null() {
+            self.out_of_memory(layout);
+            return ptr;
+        }
 
+        #[cfg(feature = "track")]
+        self.tracker.record(ptr, layout.size(), Location::caller());
         ptr
     }
 
-    pub fn grow(&mut self, size: usize) -> *mut u8 {
-        if size > self.max_bytes - self.used_bytes {
+    // Once per crossing, and never from inside the handler itself
+    #[cold]
+    fn relieve_pressure(&mut self, layout: Layout) {
+        if let Some(handler) = self.on_pressure {
+            if !self.in_pressure {
+                self.in_pressure = true;
+                handler(self, layout);
+                self.in_pressure = false;
+            }
+        }
+    }
+
+    #[cold]
+    fn out_of_memory(&self, layout: Layout) {
+        if let Some(handler) = self.on_oom {
+            handler(layout, &self.stats);
+        }
+    }
+
+    fn allocate_untracked(&mut self, size: usize, align: usize) -> *mut u8 {
+        if self.charged_size(size, align) > self.max_bytes.saturating_sub(self.used_bytes) {
             return null_mut(); // Out of memory
         }
 
-        // Align size to cache line and chunk boundaries
-        let aligned_size = (size + self.align_mask) & !self.align_mask;
-        let aligned_chunk = (aligned_size + self.chunk_mask) & !self.chunk_mask;
-
-        // Check if current chunk is sufficient
-        if self.current_offset + aligned_chunk > self.chunk_size {
-            // Allocate new chunk if needed
-            self.current_chunk = unsafe {
-                core::alloc::alloc_zeroed(
-                    core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-                )
-            };
-            self.current_offset = 0;
+        #[cfg(target_os = "linux")]
+        if let Some(mode) = self.guard {
+            let ptr = unsafe { guard::alloc(size, align, mode) };
+            if !ptr.is_null() {
+                let data = guard::data_len(size);
+                self.stats.direct_count += 1;
+                self.charge(size, data, data);
+            }
+            return ptr;
         }
 
-        // Allocate memory from current chunk
-        let ptr = unsafe { self.current_chunk.add(self.current_offset as usize) };
-        self.current_offset += aligned_chunk as u32;
-        self.used_bytes += aligned_size;
+        if let Some(class) = self.slab_class(size, align) {
+            let ptr = self.allocate_small(class);
+            if !ptr.is_null() {
+                self.charge(size, class_size(class), class_size(class));
+            }
+            return ptr;
+        }
 
+        // Align size to pointer width, chunks are page aligned so offsets line up
+        let aligned_size = (size + self.align_mask) & !self.align_mask;
+        if self.is_direct(aligned_size, align) {
+            let ptr = self.map_direct(aligned_size, align);
+            if !ptr.is_null() {
+                let mapped = Self::direct_size(aligned_size);
+                if !self.direct.push(ptr, mapped, align.max(PAGE_SIZE)) {
+                    unsafe { self.source.unmap(ptr, mapped, align.max(PAGE_SIZE)) };
+                    return null_mut(); // Nowhere to remember it, clear() would leak it
+                }
+                self.stats.direct_count += 1;
+                self.charge(size, mapped, mapped);
+            }
+            return ptr;
+        }
+
+        let ptr = self.allocate_block(aligned_size, align);
+        if !ptr.is_null() {
+            self.charge(size, aligned_size, block_size(aligned_size) - HEADER);
+        }
         ptr
     }
 
-    pub fn dealloc(&mut self, ptr: *mut u8) {
-        unsafe {
-            core::alloc::dealloc(
-                ptr,
-                core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-            );
+    // What `allocate_untracked` will add to used_bytes, rounding and all
+    #[inline(always)]
+    fn charged_size(&self, size: usize, align: usize) -> usize {
+        #[cfg(target_os = "linux")]
+        if self.guard.is_some() {
+            return guard::data_len(size);
         }
+        if let Some(class) = self.slab_class(size, align) {
+            return class_size(class);
+        }
+        let aligned_size = (size + self.align_mask) & !self.align_mask;
+        if self.is_direct(aligned_size, align) {
+            return Self::direct_size(aligned_size);
+        }
+        aligned_size
     }
 
-    pub fn shrink(&mut self, size: usize) -> *mut u8 {
-        if size > self.used_bytes {
-            return null_mut(); // Cannot shrink beyond used bytes
+    // A page aligned slab of a page needs a chunk of three: one for the
+    // headers to sit in front of it, one for the slab, one for the epilogue.
+    // Anything smaller would map a chunk per slab request and still not fit,
+    // so small requests get plain blocks there.
+    #[inline(always)]
+    fn slab_class(&self, size: usize, align: usize) -> Option<usize> {
+        if (self.chunk_size as usize) < 3 * PAGE_SIZE {
+            return None;
         }
+        class_of(size, align)
+    }
 
-        // Align size to cache line and chunk boundaries
-        let aligned_size = (size + self.align_mask) & !self.align_mask;
-        let aligned_chunk = (aligned_size + self.chunk_mask) & !self.chunk_mask;
+    #[inline]
+    fn allocate_small(&mut self, class: usize) -> *mut u8 {
+        let mut ptr = self.slabs.pop(class);
+        if ptr.is_null() {
+            // Small regions get small slabs, a quarter chunk still isn't direct
+            let slab_size = (self.chunk_size as usize >> 2).clamp(PAGE_SIZE, SLAB_SIZE);
+            let slab_size = slab_size & !(PAGE_SIZE - 1);
+            let slab = self.allocate_block(slab_size, PAGE_SIZE);
+            if slab.is_null() {
+                return null_mut(); // Allocation failed
+            }
+            self.slabs.refill(class, slab, slab_size);
+            ptr = self.slabs.pop(class);
+        }
+        ptr
+    }
 
-        // Check if current chunk is sufficient
-        if self.current_offset < aligned_chunk {
-            // Deallocate current chunk if needed
-            unsafe {
-                core::alloc::dealloc(
-                    self.current_chunk,
-                    core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-                );
+    /// Block out of the free list if anything fits, off the bump frontier if
+    /// not. Returns the payload, no accounting.
+    fn allocate_block(&mut self, payload: usize, align: usize) -> *mut u8 {
+        let align = align.max(BLOCK_ALIGN);
+        let size = block_size(payload);
+        unsafe {
+            let mut block = self.take_free(size, align);
+            if block.is_null() {
+                block = self.bump_block(size, align);
+                if block.is_null() {
+                    return null_mut(); // Allocation failed
+                }
             }
-            self.current_chunk = null_mut();
-            self.current_offset = 0;
+            BlockHeader::payload(block)
         }
+    }
 
-        // Deallocate memory from current chunk
-        let ptr = unsafe { self.current_chunk.add(self.current_offset as usize) };
-        self.current_offset -= aligned_chunk as u32;
-        self.used_bytes -= aligned_size;
+    // First fit, leading slop big enough to be a block goes back on the list
+    unsafe fn take_free(&mut self, size: usize, align: usize) -> *mut BlockHeader {
+        unsafe {
+            if self.largest_free_chunk.is_null()
+                || BlockHeader::size(self.largest_free_chunk) < size
+            {
+                return null_mut(); // Nothing would fit, don't bother walking
+            }
 
-        ptr
+            let mut block = self.free_chunks;
+            while !block.is_null() {
+                let lead = Self::lead_for(block, align);
+                if lead + size <= BlockHeader::size(block) {
+                    break;
+                }
+                block = (*BlockHeader::links(block)).next;
+            }
+            if block.is_null() {
+                return null_mut();
+            }
+
+            self.unlink_free(block);
+            let lead = Self::lead_for(block, align);
+            #[cfg(feature = "poison")]
+            let freed = Self::check_poison(block, lead, size);
+            if lead != 0 {
+                let total = BlockHeader::size(block);
+                BlockHeader::set(block, lead, false);
+                self.link_free(block);
+                block = BlockHeader::write((block as *mut u8).add(lead), total - lead, lead, false);
+                BlockHeader::set_prev_size(BlockHeader::next(block), total - lead);
+            }
+
+            self.split_used(block, size);
+            #[cfg(feature = "poison")]
+            {
+                // The tail is still painted, it just needs to know whose paint it is
+                let tail = BlockHeader::next(block);
+                if !BlockHeader::is_used(tail) {
+                    let tail_len = BlockHeader::size(tail) - HEADER;
+                    poison::tag(BlockHeader::payload(tail), BLOCK_LINK, tail_len, freed);
+                }
+            }
+            block
+        }
     }
 
-    pub fn realloc(&mut self, ptr: *mut u8, new_size: usize) -> *mut u8 {
-        if new_size > self.max_bytes - self.used_bytes {
-            return null_mut(); // Out of memory
+    // Only the bytes about to be handed out, the rest waits for its own turn.
+    // Returns the size the block was freed with, for whatever gets split off.
+    #[cfg(feature = "poison")]
+    unsafe fn check_poison(block: *mut BlockHeader, lead: usize, size: usize) -> usize {
+        unsafe {
+            let payload = BlockHeader::payload(block);
+            let len = BlockHeader::size(block) - HEADER;
+            let from = poison::start(BLOCK_LINK, len).max(lead.saturating_sub(HEADER));
+            poison::check(payload, BLOCK_LINK, from, lead + size - HEADER);
+            poison::size(payload, BLOCK_LINK, len)
         }
+    }
 
-        // Align size to cache line and chunk boundaries
-        let aligned_size = (new_size + self.align_mask) & !self.align_mask;
-        let aligned_chunk = (aligned_size + self.chunk_mask) & !self.chunk_mask;
+    // Bytes to skip so the payload lands on `align`, zero or a whole block
+    #[inline(always)]
+    unsafe fn lead_for(block: *mut BlockHeader, align: usize) -> usize {
+        let start = block as usize;
+        let mut payload = (start + HEADER + align - 1) & !(align - 1);
+        if payload - HEADER != start && payload - HEADER - start < MIN_BLOCK {
+            payload = (start + HEADER + MIN_BLOCK + align - 1) & !(align - 1);
+        }
+        payload - HEADER - start
+    }
 
-        // Check if current chunk is sufficient
-        if self.current_offset + aligned_chunk > self.chunk_size {
-            // Allocate new chunk if needed
-            self.current_chunk = unsafe {
-                core::alloc::alloc_zeroed(
-                    core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-                )
-            };
-            self.current_offset = 0;
+    // Marks `block` used at `size`, any tail worth keeping becomes a free block.
+    // Free blocks never border the frontier or another free block, so the
+    // tail can't need merging.
+    unsafe fn split_used(&mut self, block: *mut BlockHeader, size: usize) {
+        unsafe {
+            let total = BlockHeader::size(block);
+            if total - size >= MIN_BLOCK {
+                BlockHeader::set(block, size, true);
+                let tail = BlockHeader::write((block as *mut u8).add(size), total - size, size, false);
+                BlockHeader::set_prev_size(BlockHeader::next(tail), total - size);
+                self.link_free(tail);
+            } else {
+                BlockHeader::set(block, total, true);
+            }
         }
+    }
 
-        // Reallocate memory from current chunk
-        let new_ptr = unsafe { self.current_chunk.add(self.current_offset as usize) };
-        self.current_offset += aligned_chunk as u32;
-        self.used_bytes += aligned_size;
+    // Carves a block off the bump frontier so its payload lands on `align`
+    unsafe fn bump_block(&mut self, size: usize, align: usize) -> *mut BlockHeader {
+        unsafe {
+            let mut fresh = false;
+            loop {
+                if !self.current_chunk.is_null() {
+                    let frontier = self.current_chunk.add(self.current_offset as usize);
+                    let payload = ((frontier as usize + HEADER + align - 1) & !(align - 1)) as *mut u8;
+                    let header = payload.sub(HEADER);
+                    let limit = self.current_chunk.add(self.chunk_size as usize - HEADER);
+                    if header.wrapping_add(size) <= limit {
+                        let mut prev_size = self.last_size();
+                        let gap = header as usize - frontier as usize;
+                        if gap >= MIN_BLOCK {
+                            let slop = BlockHeader::write(frontier, gap, prev_size, false);
+                            #[cfg(feature = "poison")]
+                            poison::mark(BlockHeader::payload(slop), BLOCK_LINK, gap - HEADER, 0);
+                            self.link_free(slop);
+                            prev_size = gap;
+                        } else if gap != 0 {
+                            // Too small to track, the block to the left keeps it
+                            BlockHeader::set(self.last_block, prev_size + gap, true);
+                            prev_size += gap;
+                        }
+                        let block = BlockHeader::write(header, size, prev_size, true);
+                        self.last_block = block;
+                        let offset = header as usize + size - self.current_chunk as usize;
+                        self.current_offset = offset as u32;
+                        return block;
+                    }
+                }
+                if fresh || !self.new_chunk() {
+                    return null_mut(); // Allocation failed
+                }
+                fresh = true;
+            }
+        }
+    }
 
+    #[inline(always)]
+    unsafe fn last_size(&self) -> usize {
         unsafe {
-            core::ptr::copy_nonoverlapping(ptr, new_ptr, aligned_size);
-            core::alloc::dealloc(
-                ptr,
-                core::alloc::Layout::from_size_align(self.chunk_size as usize, 1).unwrap(),
-            );
+            if self.last_block.is_null() { 0 } else { BlockHeader::size(self.last_block) }
         }
+    }
 
-        new_ptr
+    #[inline(always)]
+    fn is_frontier(&self, block: *mut BlockHeader) -> bool {
+        block as *mut u8 == self.current_chunk.wrapping_add(self.current_offset as usize)
     }
-    pub fn defrag(&mut self) {
-        if self.current_chunk.is_null() {
-            return false;
+
+    // Retires the current chunk (tail goes on the free list) and maps a new one
+    unsafe fn new_chunk(&mut self) -> bool {
+        unsafe {
+            let chunk_size = self.chunk_size as usize;
+            let chunk = self.source.map(chunk_size, PAGE_SIZE);
+            if chunk.is_null() {
+                return false;
+            }
+
+            if !self.current_chunk.is_null() {
+                let frontier = self.current_chunk.add(self.current_offset as usize);
+                let tail = chunk_size - HEADER - self.current_offset as usize;
+                if tail >= MIN_BLOCK {
+                    let block = BlockHeader::write(frontier, tail, self.last_size(), false);
+                    #[cfg(feature = "poison")]
+                    poison::mark(BlockHeader::payload(block), BLOCK_LINK, tail - HEADER, 0);
+                    self.link_free(block);
+                } else if tail != 0 && !self.last_block.is_null() {
+                    BlockHeader::set(self.last_block, self.last_size() + tail, true);
+                }
+            }
+
+            let header = chunk as *mut ChunkHeader;
+            (*header).next = self.chunks;
+            (*header).size = chunk_size;
+            self.chunks = header;
+            self.stats.chunk_count += 1;
+            BlockHeader::write_epilogue(chunk.add(chunk_size - HEADER));
+
+            self.current_chunk = chunk;
+            self.current_offset = CHUNK_HEADER as u32;
+            self.last_block = null_mut();
+            true
         }
+    }
 
+    // Merges with free neighbours, then either rolls the frontier back or lists it
+    unsafe fn free_block(&mut self, mut block: *mut BlockHeader) {
         unsafe {
-            unsafe {
-                // What seems to work best, is to reserve a cache line,
-                // then scan and compact, then update the allocator state.
-                // I'm using a left-shift based on space.
-
-                // Phase 1: Reserve cache space
-                let cache_reserve =
-                    ((self.prefetch_size as usize) + self.align_mask) & !self.align_mask;
-                let mut write_ptr = self.current_chunk.add(cache_reserve);
-                let end_ptr = self.current_chunk.add(self.used_bytes as usize);
-                let mut read_ptr = write_ptr;
-
-                // Phase 2 & 3: Scan and compact
-                while read_ptr < end_ptr {
-                    // Find next non-zero block
-                    let mut block_start = read_ptr;
-                    while block_start < end_ptr && *block_start == 0 {
-                        block_start = block_start.add(1);
-                    }
+            let mut size = BlockHeader::size(block);
+
+            let next = BlockHeader::next(block);
+            if !self.is_frontier(next) && !BlockHeader::is_used(next) {
+                self.unlink_free(next);
+                size += BlockHeader::size(next);
+                #[cfg(feature = "poison")]
+                Self::paint_over(next);
+            }
 
-                    if block_start >= end_ptr {
-                        break;
-                    }
+            let prev = BlockHeader::prev(block);
+            if !prev.is_null() && !BlockHeader::is_used(prev) {
+                self.unlink_free(prev);
+                #[cfg(feature = "poison")]
+                {
+                    // Prev keeps the paint, but the size it reports is ours now
+                    let len = BlockHeader::size(prev) + size - HEADER;
+                    let own = BlockHeader::size(block) - HEADER;
+                    let freed = poison::size(BlockHeader::payload(block), BLOCK_LINK, own);
+                    Self::paint_over(block);
+                    poison::tag(BlockHeader::payload(prev), BLOCK_LINK, len, freed);
+                }
+                size += BlockHeader::size(prev);
+                block = prev;
+            }
 
-                    // Find block end
-                    let mut block_end = block_start;
-                    while block_end < end_ptr && *block_end != 0 {
-                        block_end = block_end.add(1);
-                    }
+            BlockHeader::set(block, size, false);
+            let next = BlockHeader::next(block);
+            if self.is_frontier(next) {
+                // Last one in gets to be the first one out
+                self.current_offset = (block as usize - self.current_chunk as usize) as u32;
+                self.last_block = BlockHeader::prev(block);
+                return;
+            }
+            BlockHeader::set_prev_size(next, size);
+            self.link_free(block);
+        }
+    }
 
-                    // Calculate aligned size
-                    let block_size = block_end.offset_from(block_start) as usize;
-                    let aligned_size = (block_size + self.align_mask) & !self.align_mask;
+    // A block swallowed by its left neighbour, its header and list bits are
+    // just payload now
+    #[cfg(feature = "poison")]
+    #[inline]
+    unsafe fn paint_over(block: *mut BlockHeader) {
+        unsafe {
+            let len = BlockHeader::size(block) - HEADER;
+            poison::fill(block as *mut u8, HEADER + poison::start(BLOCK_LINK, len));
+        }
+    }
 
-                    // Move block if needed
-                    if write_ptr != block_start {
-                        std::ptr::copy(block_start, write_ptr, block_size);
-                        // Zero out old location
-                        std::ptr::write_bytes(block_start, 0, block_size);
-                    }
+    #[inline]
+    unsafe fn link_free(&mut self, block: *mut BlockHeader) {
+        unsafe {
+            let links = BlockHeader::links(block);
+            (*links).prev = null_mut();
+            (*links).next = self.free_chunks;
+            if !self.free_chunks.is_null() {
+                (*BlockHeader::links(self.free_chunks)).prev = block;
+            }
+            self.free_chunks = block;
 
-                    // Update write pointer with alignment
-                    write_ptr = write_ptr.add(aligned_size);
-                    read_ptr = block_end;
-                }
+            if self.largest_free_chunk.is_null()
+                || BlockHeader::size(block) > BlockHeader::size(self.largest_free_chunk)
+            {
+                self.largest_free_chunk = block;
+            }
+        }
+    }
+
+    #[inline]
+    unsafe fn unlink_free(&mut self, block: *mut BlockHeader) {
+        unsafe {
+            let links = BlockHeader::links(block);
+            let (next, prev) = ((*links).next, (*links).prev);
+            if prev.is_null() {
+                self.free_chunks = next;
+            } else {
+                (*BlockHeader::links(prev)).next = next;
+            }
+            if !next.is_null() {
+                (*BlockHeader::links(next)).prev = prev;
+            }
+
+            if block == self.largest_free_chunk {
+                self.rescan_largest();
+            }
+        }
+    }
 
-                // Phase 4: Update allocator state
-                let new_used = write_ptr.offset_from(self.current_chunk) as u32;
-                if new_used < self.used_bytes {
-                    self.used_bytes = new_used;
-                    self.free_bytes = self.max_bytes - self.used_bytes;
-                    true
-                } else {
-                    false
+    // Only when the largest leaves the list, so the walk is rare
+    unsafe fn rescan_largest(&mut self) {
+        unsafe {
+            let mut largest: *mut BlockHeader = null_mut();
+            let mut block = self.free_chunks;
+            while !block.is_null() {
+                if largest.is_null() || BlockHeader::size(block) > BlockHeader::size(largest) {
+                    largest = block;
                 }
+                block = (*BlockHeader::links(block)).next;
             }
+            self.largest_free_chunk = largest;
         }
     }
-    pub fn prune(&mut self) -> bool {
-        if self.current_chunk.is_null() {
-            return false;
+
+    // Anything over a quarter chunk wastes too much tail, let the kernel have it
+    #[inline(always)]
+    fn is_direct(&self, aligned_size: usize, align: usize) -> bool {
+        aligned_size > (self.chunk_size as usize >> 2) || align > PAGE_SIZE
+    }
+
+    #[inline(always)]
+    fn direct_size(aligned_size: usize) -> usize {
+        (aligned_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    #[inline]
+    fn map_direct(&mut self, aligned_size: usize, align: usize) -> *mut u8 {
+        unsafe { self.source.map(Self::direct_size(aligned_size), align.max(PAGE_SIZE)) }
+    }
+
+    /// Returns a block from `allocate`, with the same layout.
+    /// Small blocks go back on their class list, chunk blocks merge with their
+    /// free neighbours and go on the free list.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have come from this allocator with `layout`, and
+    /// not been freed since.
+    #[inline]
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "track")]
+        self.tracker.forget(ptr);
+        unsafe { self.dealloc_untracked(ptr, layout.size(), layout.align()) };
+    }
+
+    unsafe fn dealloc_untracked(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        if ptr.is_null() {
+            return;
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.guard.is_some() {
+            unsafe { guard::free(ptr, size) };
+            let data = guard::data_len(size);
+            self.stats.direct_count -= 1;
+            self.stats.guard_bytes += guard::mapped_len(size, align);
+            self.refund(size, data, data);
+            return;
+        }
+
+        if let Some(class) = self.slab_class(size, align) {
+            #[cfg(feature = "poison")]
+            unsafe { poison::mark(ptr, SLAB_LINK, class_size(class), size) };
+            self.slabs.push(class, ptr);
+            self.refund(size, class_size(class), class_size(class));
+            return;
+        }
+
+        let aligned_size = (size + self.align_mask) & !self.align_mask;
+        if self.is_direct(aligned_size, align) {
+            let mapped = Self::direct_size(aligned_size);
+            self.direct.remove(ptr);
+            unsafe { self.source.unmap(ptr, mapped, align.max(PAGE_SIZE)) };
+            self.stats.direct_count -= 1;
+            self.refund(size, mapped, mapped);
+            return;
         }
 
         unsafe {
-            // Start from end of used memory
-            let mut end = self.current_chunk.add(self.used_bytes as usize);
-            let start = self.current_chunk;
+            let block = BlockHeader::from_payload(ptr);
+            #[cfg(feature = "poison")]
+            poison::mark(ptr, BLOCK_LINK, BlockHeader::size(block) - HEADER, size);
+            self.free_block(block);
+        }
+        self.refund(size, aligned_size, block_size(aligned_size) - HEADER);
+    }
 
-            // Scan backward for last non-zero byte
-            while end > start && *end.sub(1) == 0 {
-                end = end.sub(1);
-            }
+    /// `ptr` from `old` to `new`, contents up to the smaller size survive.
+    /// Null and `ptr` untouched if `new` doesn't fit.
+    ///
+    /// # Safety
+    /// Same as `dealloc`, with `old` the layout `ptr` was allocated with.
+    /// `ptr` is gone unless this returns null.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    pub unsafe fn grow(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        debug_assert!(new.size() >= old.size(), "grow can't shrink");
+        unsafe { self.reallocate(ptr, old, new) }
+    }
 
-            // Calculate new size with alignments
-            let base_size = end.offset_from(start) as usize;
-            let cache_pad = self.prefetch_size as usize;
-            let aligned_size = (base_size + cache_pad + self.align_mask) & !self.align_mask;
+    /// # Safety
+    /// Same as `grow`.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    pub unsafe fn shrink(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        debug_assert!(new.size() <= old.size(), "shrink can't grow");
+        unsafe { self.reallocate(ptr, old, new) }
+    }
 
-            // Update allocator state if smaller
-            if aligned_size < (self.used_bytes as usize) {
-                self.used_bytes = aligned_size as u32;
-                self.free_bytes = self.max_bytes - self.used_bytes;
-                true
-            } else {
-                false
+    /// `GlobalAlloc::realloc` shape, alignment stays what `layout` says.
+    ///
+    /// # Safety
+    /// Same as `grow`.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new) => unsafe { self.reallocate(ptr, layout, new) },
+            Err(_) => null_mut(), // Size overflows once aligned
+        }
+    }
+
+    // Stays put when it can, moves and copies the smaller size when it can't
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn reallocate(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        if ptr.is_null() {
+            return self.allocate(new);
+        }
+        // Only the growth counts, shrinking never crosses a limit
+        if new.size() > old.size() && !self.admit(new, new.size() - old.size()) {
+            return null_mut(); // Old block stays valid
+        }
+
+        if ptr as usize & (new.align() - 1) == 0 && self.resize_in_place(ptr, old, new) {
+            #[cfg(feature = "track")]
+            {
+                self.tracker.forget(ptr);
+                self.tracker.record(ptr, new.size(), Location::caller());
             }
+            return ptr;
         }
+
+        let new_ptr = self.allocate_admitted(new);
+        if new_ptr.is_null() {
+            return null_mut(); // Old block stays valid
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, old.size().min(new.size()));
+            self.dealloc(ptr, old);
+        }
+        new_ptr
     }
-    pub fn trim(&mut self) -> bool {
-        if self.current_chunk.is_null() {
+
+    // Same size class, same mapping size, or a chunk block that can split its
+    // tail off or grow into whatever's free on its right. Accounting included.
+    fn resize_in_place(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.guard.is_some() {
+            return false; // The guard page sits right behind the old size
+        }
+
+        let (old_size, new_size) = (old.size(), new.size());
+        match (self.slab_class(old_size, old.align()), self.slab_class(new_size, new.align())) {
+            (Some(from), Some(to)) if from == to => {
+                let granted = class_size(from);
+                self.refund(old_size, granted, granted);
+                self.charge(new_size, granted, granted);
+                return true;
+            }
+            (None, None) => {}
+            _ => return false,
+        }
+
+        let old_aligned = (old_size + self.align_mask) & !self.align_mask;
+        let new_aligned = (new_size + self.align_mask) & !self.align_mask;
+        match (self.is_direct(old_aligned, old.align()), self.is_direct(new_aligned, new.align())) {
+            (true, true) => {
+                let mapped = Self::direct_size(old_aligned);
+                if Self::direct_size(new_aligned) != mapped {
+                    return false; // Mappings don't grow, and shrinking one is a move anyway
+                }
+                self.refund(old_size, mapped, mapped);
+                self.charge(new_size, mapped, mapped);
+                return true;
+            }
+            (false, false) => {}
+            _ => return false,
+        }
+
+        let headroom = self.max_bytes.saturating_sub(self.used_bytes);
+        if new_aligned > old_aligned && new_aligned - old_aligned > headroom {
+            return false; // Over the hard limit, moving won't fit either
+        }
+        let block = unsafe { BlockHeader::from_payload(ptr) };
+        if !unsafe { self.resize_block(block, block_size(new_aligned), old_size) } {
             return false;
         }
+        self.refund(old_size, old_aligned, block_size(old_aligned) - HEADER);
+        self.charge(new_size, new_aligned, block_size(new_aligned) - HEADER);
+        true
+    }
 
+    // Shrinks by freeing the tail, grows into the bump frontier or a free
+    // right neighbour. `freed` is the old allocation size, for the poison tag.
+    #[cfg_attr(not(feature = "poison"), allow(unused_variables))]
+    unsafe fn resize_block(&mut self, block: *mut BlockHeader, size: usize, freed: usize) -> bool {
         unsafe {
-            // Find first used byte
-            let mut start = self.current_chunk;
-            let mut end = self.current_chunk.add(self.used_bytes as usize);
-
-            // Scan forward for first non-zero byte
-            while start < end && *start == 0 {
-                start = start.add(1);
+            let total = BlockHeader::size(block);
+            if size <= total {
+                if total - size >= MIN_BLOCK {
+                    BlockHeader::set(block, size, true);
+                    let tail = BlockHeader::write((block as *mut u8).add(size), total - size, size, true);
+                    #[cfg(feature = "poison")]
+                    poison::mark(BlockHeader::payload(tail), BLOCK_LINK, total - size - HEADER, freed);
+                    self.free_block(tail); // Merges right or rolls the frontier back
+                }
+                return true;
             }
 
-            // Scan backward for last non-zero byte
-            while end > start && *end.sub(1) == 0 {
-                end = end.sub(1);
+            let next = BlockHeader::next(block);
+            if self.is_frontier(next) {
+                let limit = self.current_chunk as usize + self.chunk_size as usize - HEADER;
+                if block as usize + size > limit {
+                    return false;
+                }
+                BlockHeader::set(block, size, true);
+                self.current_offset = (block as usize + size - self.current_chunk as usize) as u32;
+                return true;
+            }
+            if BlockHeader::is_used(next) || total + BlockHeader::size(next) < size {
+                return false;
             }
 
-            // Calculate new size needed (with alignment)
-            let used_size = end.offset_from(start) as usize;
-            let aligned_size = (used_size + self.align_mask) & !self.align_mask;
+            // Free blocks never border each other or the frontier, the tail
+            // split_used leaves behind can't need merging
+            self.unlink_free(next);
+            #[cfg(feature = "poison")]
+            let freed = Self::check_poison(next, 0, size - total);
+            let merged = total + BlockHeader::size(next);
+            BlockHeader::set(block, merged, true);
+            BlockHeader::set_prev_size(BlockHeader::next(block), merged);
+            self.split_used(block, size);
+            #[cfg(feature = "poison")]
+            {
+                let tail = BlockHeader::next(block);
+                if !BlockHeader::is_used(tail) {
+                    let tail_len = BlockHeader::size(tail) - HEADER;
+                    poison::tag(BlockHeader::payload(tail), BLOCK_LINK, tail_len, freed);
+                }
+            }
+            true
+        }
+    }
 
-            // Update allocator state
-            self.max_bytes = aligned_size as u32;
-            self.used_bytes = used_size as u32;
-            self.free_bytes = self.max_bytes - self.used_bytes;
+    /// Hands chunks with nothing live left in them back to the kernel and
+    /// returns how many bytes that was. The chunk being bumped stays put.
+    /// Nothing moves, raw pointers can't be patched. `CompactHeap` can.
+    pub fn trim(&mut self) -> usize {
+        let mut released = 0;
+        let mut link: *mut *mut ChunkHeader = &mut self.chunks;
+        unsafe {
+            while !(*link).is_null() {
+                let chunk = *link;
+                let first = (chunk as *mut u8).add(CHUNK_HEADER) as *mut BlockHeader;
+
+                // One free block from the header to the epilogue means empty
+                let empty = chunk as *mut u8 != self.current_chunk
+                    && !BlockHeader::is_used(first)
+                    && BlockHeader::size(BlockHeader::next(first)) == 0;
+                if !empty {
+                    link = &mut (*chunk).next;
+                    continue;
+                }
 
-            // Reallocate memory if needed
-            let new_chunk = self.reallocate(self.current_chunk, aligned_size);
-            if !new_chunk.is_null() {
-                self.current_chunk = new_chunk;
-                true
-            } else {
-                false
+                self.unlink_free(first);
+                *link = (*chunk).next;
+                released += (*chunk).size;
+                self.stats.chunk_count -= 1;
+                self.source.unmap(chunk as *mut u8, (*chunk).size, PAGE_SIZE);
             }
         }
+        released
     }
+
+    /// Everything goes back at once, except live guarded allocations, those
+    /// aren't tracked anywhere and stay mapped.
     pub fn clear(&mut self) {
-        // Quick and Dirty, writing 0's takes time.
+        // Quick and Dirty, writing 0's takes time. Chunks go straight back.
+        let mut chunk = self.chunks;
+        while !chunk.is_null() {
+            unsafe {
+                let next = (*chunk).next;
+                self.source.unmap(chunk as *mut u8, (*chunk).size, PAGE_SIZE);
+                chunk = next;
+            }
+        }
+        while let Some(map) = self.direct.pop() {
+            unsafe { self.source.unmap(map.ptr, map.size, map.align) };
+        }
+        self.chunks = null_mut();
+        self.last_block = null_mut();
         self.current_chunk = null_mut();
         self.current_offset = 0;
+        self.slabs = SlabClasses::new();
         self.free_chunks = null_mut();
         self.largest_free_chunk = null_mut();
         self.free_bytes = self.max_bytes;
         self.used_bytes = 0;
+        self.in_pressure = false;
+
+        // Totals survive, they're history
+        self.stats.current_bytes = 0;
+        self.stats.padding_bytes = 0;
+        self.stats.chunk_count = 0;
+        self.stats.direct_count = 0;
+
+        #[cfg(feature = "track")]
+        self.tracker.clear();
     }
 }
 
-impl Drop for Allocator {
+impl<S: ChunkSource> Drop for Allocator<S> {
     fn drop(&mut self) {
+        // Whatever is still live now is a leak, say where it came from
+        #[cfg(feature = "track")]
+        self.report_leaks();
         self.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static PRESSURE: AtomicUsize = AtomicUsize::new(0);
+    static OOM: AtomicUsize = AtomicUsize::new(0);
+    static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+    // OsChunks that keeps count of what it still has out
+    struct Counted;
+
+    impl ChunkSource for Counted {
+        unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8 {
+            let ptr = unsafe { OsChunks.map(size, align) };
+            if !ptr.is_null() {
+                MAPPED.fetch_add(size, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn unmap(&mut self, ptr: *mut u8, size: usize, align: usize) {
+            MAPPED.fetch_sub(size, Ordering::Relaxed);
+            unsafe { OsChunks.unmap(ptr, size, align) };
+        }
+    }
+
+    fn count_pressure(_: &mut Allocator, _: Layout) {
+        PRESSURE.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_oom(_: Layout, _: &AllocStats) {
+        OOM.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_clear_unmaps_direct() {
+        let mut heap = Allocator::with_source(Counted);
+        let big = Layout::from_size_align(1 << 20, 8).unwrap();
+        let wide = Layout::from_size_align(64, PAGE_SIZE * 4).unwrap();
+        for _ in 0..300 {
+            assert!(!heap.allocate(big).is_null());
+        }
+        let ptr = heap.allocate(wide);
+        assert_eq!(ptr as usize % (PAGE_SIZE * 4), 0);
+        unsafe { heap.dealloc(ptr, wide) };
+        assert!(!heap.allocate(wide).is_null());
+        assert!(!heap.allocate(Layout::from_size_align(64, 8).unwrap()).is_null());
+        assert_eq!(heap.stats().direct_count, 301);
+
+        heap.clear();
+        assert_eq!(MAPPED.load(Ordering::Relaxed), 0);
+
+        // Still works after, and Drop gives it all back too
+        assert!(!heap.allocate(big).is_null());
+        drop(heap);
+        assert_eq!(MAPPED.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let mut heap = Allocator::new();
+        let mut live = [(null_mut(), Layout::new::<u8>()); 14 * 6];
+        for shift in 0..14 {
+            let align = 1 << shift; // Up to two pages, past one is direct
+            for (i, size) in [1, 7, 100, 3000, 5000, 70_000].into_iter().enumerate() {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = heap.allocate(layout);
+                assert_eq!(ptr as usize % align, 0, "size {} align {}", size, align);
+                unsafe { ptr.write_bytes(0xab, size) };
+                live[shift * 6 + i] = (ptr, layout);
+            }
+        }
+        for (ptr, layout) in live {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_free_blocks_coalesce() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(20_000, 8).unwrap();
+        let [a, b, c, _fence] = core::array::from_fn(|_| heap.allocate(layout));
+
+        // Both ends first, then the middle glues all three together
+        unsafe {
+            heap.dealloc(a, layout);
+            heap.dealloc(c, layout);
+            heap.dealloc(b, layout);
+        }
+        let whole = heap.allocate(Layout::from_size_align(60_000, 8).unwrap());
+        assert_eq!(whole, a);
+    }
+
+    #[test]
+    fn test_trim_releases_empty_chunks() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(200_000, 8).unwrap();
+        let ptrs: [*mut u8; 6] = core::array::from_fn(|_| heap.allocate(layout));
+        assert_eq!(heap.stats().chunk_count, 2);
+        assert_eq!(heap.trim(), 0); // All live
+
+        for ptr in ptrs {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.trim(), 1 << 20); // The one being bumped stays
+        assert_eq!(heap.stats().chunk_count, 1);
+        assert_eq!(heap.used_bytes(), 0);
+        assert!(!heap.allocate(layout).is_null());
+    }
+
+    #[test]
+    fn test_realloc_in_place() {
+        let mut heap = Allocator::new();
+        let l = |size| Layout::from_size_align(size, 16).unwrap();
+
+        // At the frontier it just bumps further, shrinking rolls it back
+        let ptr = heap.allocate(l(10_000));
+        unsafe { ptr.write_bytes(7, 10_000) };
+        assert_eq!(unsafe { heap.reallocate(ptr, l(10_000), l(50_000)) }, ptr);
+        assert_eq!(unsafe { *ptr.add(9_999) }, 7);
+        assert_eq!(unsafe { heap.reallocate(ptr, l(50_000), l(20_000)) }, ptr);
+        let next = heap.allocate(l(5000)); // Past the slab classes
+        assert_eq!(next as usize, ptr as usize + block_size(20_000));
+
+        // Grows into a free right neighbour, what's left of it stays usable
+        let neighbour = heap.allocate(l(30_000));
+        let _fence = heap.allocate(l(5000));
+        let grower = next;
+        unsafe { heap.dealloc(neighbour, l(30_000)) };
+        assert_eq!(unsafe { heap.reallocate(grower, l(5000), l(20_000)) }, grower);
+        let rest = heap.allocate(l(9000));
+        assert_eq!(rest as usize, grower as usize + block_size(20_000));
+
+        // Too big for the neighbour moves, same class or mapping size stays
+        let moved = unsafe { heap.reallocate(grower, l(20_000), l(40_000)) };
+        assert_ne!(moved, grower);
+        let slot = heap.allocate(l(100));
+        assert_eq!(unsafe { heap.reallocate(slot, l(100), l(120)) }, slot);
+        let mapping = heap.allocate(l(300_000));
+        assert_eq!(unsafe { heap.reallocate(mapping, l(300_000), l(301_000)) }, mapping);
+        assert_eq!(heap.used_bytes(), heap.stats().current_bytes);
+    }
+
+    #[test]
+    fn test_hard_limit_counts_rounding() {
+        let mut heap = Allocator::new();
+        heap.set_oom_handler(Some(count_oom));
+        heap.set_limits(usize::MAX, 100);
+
+        // 65 bytes fit under 100, the 128 byte slab slot they'd take doesn't
+        let ptr = heap.allocate(Layout::from_size_align(65, 8).unwrap());
+        assert!(ptr.is_null());
+        assert_eq!(OOM.load(Ordering::Relaxed), 1);
+        assert_eq!(heap.used_bytes(), 0);
+
+        let ptr = heap.allocate(Layout::from_size_align(64, 8).unwrap());
+        assert!(!ptr.is_null());
+        assert!(heap.used_bytes() <= heap.max_bytes());
+        unsafe { heap.dealloc(ptr, Layout::from_size_align(64, 8).unwrap()) };
+    }
+
+    #[test]
+    fn test_realloc_admits_growth() {
+        let mut heap = Allocator::new();
+        heap.set_pressure_handler(Some(count_pressure));
+        let old = Layout::from_size_align(8192, 8).unwrap();
+        let ptr = heap.allocate(old);
+        assert!(!ptr.is_null());
+
+        // 100 bytes of soft headroom, every size below is way past that
+        let used = heap.used_bytes();
+        heap.set_limits(used + 100, usize::MAX);
+        heap.set_fault_policy(FaultPolicy::AboveSize(100));
+
+        let small = Layout::from_size_align(7168, 8).unwrap();
+        let ptr = unsafe { heap.reallocate(ptr, old, small) };
+        assert!(!ptr.is_null());
+        let ptr = unsafe { heap.reallocate(ptr, small, Layout::from_size_align(7232, 8).unwrap()) };
+        assert!(!ptr.is_null());
+        assert_eq!(PRESSURE.load(Ordering::Relaxed), 0);
+        assert_eq!(heap.faults_injected(), 0);
+
+        // Growing past the headroom still counts
+        let big = Layout::from_size_align(9216, 8).unwrap();
+        let grown = unsafe { heap.reallocate(ptr, Layout::from_size_align(7232, 8).unwrap(), big) };
+        assert!(grown.is_null());
+        assert_eq!(PRESSURE.load(Ordering::Relaxed), 1);
+        assert_eq!(heap.faults_injected(), 1);
+        unsafe { heap.dealloc(ptr, Layout::from_size_align(7232, 8).unwrap()) };
+    }
+
+    #[test]
+    fn test_fault_nth() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        heap.set_fault_policy(FaultPolicy::Nth(3));
+        let ptrs: [*mut u8; 5] = core::array::from_fn(|_| heap.allocate(layout));
+        assert_eq!(ptrs.map(|ptr| ptr.is_null()), [false, false, true, false, false]);
+        assert_eq!(heap.faults_injected(), 1);
+        for ptr in ptrs {
+            unsafe { heap.dealloc(ptr, layout) }; // Null included, that's a no-op
+        }
+    }
+
+    #[test]
+    fn test_fault_above_size() {
+        let mut heap = Allocator::new();
+        heap.set_fault_policy(FaultPolicy::AboveSize(1024));
+        let fails = [16, 1024, 1025, 300 << 10].map(|size| {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr = heap.allocate(layout);
+            unsafe { heap.dealloc(ptr, layout) };
+            ptr.is_null()
+        });
+        assert_eq!(fails, [false, false, true, true]); // Direct mappings too
+        assert_eq!(heap.faults_injected(), 2);
+    }
+
+    #[test]
+    fn test_fault_random() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let run = |seed| {
+            let mut heap = Allocator::new();
+            heap.set_fault_policy(FaultPolicy::Random { seed, one_in: 4 });
+            let fails: [bool; 256] = core::array::from_fn(|_| {
+                let ptr = heap.allocate(layout);
+                unsafe { heap.dealloc(ptr, layout) };
+                ptr.is_null()
+            });
+            (fails, heap.faults_injected())
+        };
+
+        // Same seed, same failures, and roughly one in four of them
+        let (fails, injected) = run(42);
+        assert_eq!(run(42).0, fails);
+        assert_eq!(fails.iter().filter(|&&fail| fail).count() as u64, injected);
+        assert!((32..96).contains(&injected), "{} of 256", injected);
+        assert_ne!(run(7).0, fails);
+    }
+
+    #[test]
+    fn test_fault_after_bytes() {
+        let mut heap = Allocator::new();
+        let l = |size| Layout::from_size_align(size, 8).unwrap();
+        heap.set_fault_policy(FaultPolicy::AfterBytes(1000));
+        let a = heap.allocate(l(600));
+        assert!(!a.is_null());
+        assert!(heap.allocate(l(500)).is_null()); // 1100 > 1000
+        let b = heap.allocate(l(400));
+        assert!(!b.is_null()); // Exactly the budget
+        assert!(heap.allocate(l(1)).is_null());
+
+        // A running total, freeing doesn't win any of it back
+        unsafe { heap.dealloc(a, l(600)) };
+        assert!(heap.allocate(l(1)).is_null());
+        assert_eq!(heap.faults_injected(), 3);
+
+        // Setting it again starts the count over
+        heap.set_fault_policy(FaultPolicy::AfterBytes(1000));
+        let c = heap.allocate(l(1000));
+        assert!(!c.is_null());
+        unsafe {
+            heap.dealloc(b, l(400));
+            heap.dealloc(c, l(1000));
+        }
+    }
+
+    #[test]
+    fn test_from_region() {
+        const LEN: usize = 2 << 20; // A 1MB chunk and room for direct mappings
+        static mut REGION: [u8; LEN] = [0; LEN];
+        let start = &raw mut REGION as usize;
+        let mut heap = Allocator::from_region(unsafe { &mut *core::ptr::addr_of_mut!(REGION) });
+
+        // A slab slot, a chunk block and a direct mapping, all out of REGION.
+        // Nothing else backs a StaticRegion, so no mmap can have happened.
+        let layouts = [(24, 8), (3000, 64), (20 << 10, 8), (300 << 10, PAGE_SIZE)]
+            .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+        for round in 0..2 {
+            let ptrs = layouts.map(|layout| heap.allocate(layout));
+            for (&ptr, layout) in ptrs.iter().zip(layouts) {
+                assert!(!ptr.is_null(), "round {} {:?}", round, layout);
+                assert!(ptr as usize >= start && ptr as usize + layout.size() <= start + LEN);
+                assert_eq!(ptr as usize % layout.align(), 0);
+                unsafe { ptr.write_bytes(0xa5, layout.size()) };
+            }
+            assert_eq!(heap.stats().direct_count, 1);
+            for (ptr, layout) in ptrs.into_iter().zip(layouts) {
+                unsafe { heap.dealloc(ptr, layout) };
+            }
+            assert_eq!(heap.used_bytes(), 0); // And the second round fits in what came back
+        }
+    }
+
+    #[test]
+    fn test_small_region() {
+        // Chunks come out at two pages, a page aligned slab can't fit in one
+        static mut SMALL: [u8; 3 * PAGE_SIZE] = [0; 3 * PAGE_SIZE];
+        let start = &raw mut SMALL as usize;
+        let mut heap = Allocator::from_region(unsafe { &mut *core::ptr::addr_of_mut!(SMALL) });
+
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptrs: [*mut u8; 64] = core::array::from_fn(|_| heap.allocate(layout));
+        for ptr in ptrs {
+            assert!(!ptr.is_null());
+            assert!(ptr as usize >= start && ptr as usize + 16 <= start + 3 * PAGE_SIZE);
+        }
+        assert_eq!(heap.stats().chunk_count, 1); // Not a chunk burnt per request
+
+        for ptr in ptrs {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.used_bytes(), 0);
+        assert!(!heap.allocate(layout).is_null());
+    }
+}
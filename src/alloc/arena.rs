@@ -0,0 +1,206 @@
+//! Per-request scratch space, built on the same chunks `Allocator` maps.
+//! Allocation is a bump, `rewind` goes back to a `Mark`, and `reset` throws
+//! everything away in O(1). Chunks are kept for the next round and never
+//! zeroed, writing 0's takes time. Destructors don't run, it's an arena.
+
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+use super::block::{ChunkHeader, CHUNK_HEADER};
+
+/// Where an arena was, hand it back to `Arena::rewind`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mark {
+    chunk: *mut ChunkHeader,
+    offset: usize,
+}
+
+pub struct Arena {
+    first: Cell<*mut ChunkHeader>,   // Chunks in bump order, spares hang off the end
+    current: Cell<*mut ChunkHeader>, // Null until the first alloc after a reset
+    offset: Cell<usize>,             // Bump offset inside current
+    chunk_size: usize,
+}
+
+impl Arena {
+    #[inline]
+    pub const fn new() -> Self {
+        Self::with_chunk_size(1 << 20) // 1MB chunks, same as Allocator
+    }
+
+    #[inline]
+    pub const fn with_chunk_size(chunk_size: usize) -> Self {
+        Self {
+            first: Cell::new(null_mut()),
+            current: Cell::new(null_mut()),
+            offset: Cell::new(0),
+            chunk_size: (chunk_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        }
+    }
+
+    #[inline]
+    #[allow(clippy::mut_from_ref)] // Every call hands out memory nobody else has
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        let ptr = self.alloc_raw(Layout::new::<T>()) as *mut T;
+        unsafe {
+            ptr.write(value);
+            &mut *ptr
+        }
+    }
+
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, src: &[T]) -> &mut [T] {
+        let layout = match Layout::array::<T>(src.len()) {
+            Ok(layout) => layout,
+            Err(_) => panic!("Arena exploded: {} elements don't fit a slice", src.len()),
+        };
+        let ptr = self.alloc_raw(layout) as *mut T;
+        unsafe {
+            copy_nonoverlapping(src.as_ptr(), ptr, src.len());
+            core::slice::from_raw_parts_mut(ptr, src.len())
+        }
+    }
+
+    /// Raw bytes for `layout`, explodes if the kernel won't give us a chunk.
+    #[inline]
+    pub fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.try_alloc_raw(layout);
+        if ptr.is_null() {
+            panic!(
+                "Arena exploded: no chunk for {} bytes at align {}",
+                layout.size(),
+                layout.align()
+            );
+        }
+        ptr
+    }
+
+    /// Raw bytes for `layout`, null if the kernel won't give us a chunk.
+    #[inline]
+    pub fn try_alloc_raw(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = (layout.size(), layout.align());
+        let current = self.current.get();
+        if !current.is_null() {
+            let base = current as usize;
+            let start = (base + self.offset.get() + align - 1) & !(align - 1);
+            if start + size <= base + unsafe { (*current).size } {
+                self.offset.set(start + size - base);
+                return start as *mut u8;
+            }
+        }
+        self.next_chunk(layout)
+    }
+
+    // Moves on to the next spare chunk, or maps one in right after current
+    #[cold]
+    fn next_chunk(&self, layout: Layout) -> *mut u8 {
+        let needed = CHUNK_HEADER + layout.size() + layout.align();
+        let current = self.current.get();
+        unsafe {
+            let next = if current.is_null() { self.first.get() } else { (*current).next };
+            let chunk = if !next.is_null() && (*next).size >= needed {
+                next
+            } else {
+                let chunk_size = self.chunk_size.max((needed + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+                let chunk = map_chunk(chunk_size) as *mut ChunkHeader;
+                if chunk.is_null() {
+                    return null_mut();
+                }
+                (*chunk).size = chunk_size;
+                (*chunk).next = next;
+                if current.is_null() {
+                    self.first.set(chunk);
+                } else {
+                    (*current).next = chunk;
+                }
+                chunk
+            };
+            self.current.set(chunk);
+            self.offset.set(CHUNK_HEADER);
+        }
+        self.try_alloc_raw(layout)
+    }
+
+    #[inline]
+    pub fn checkpoint(&self) -> Mark {
+        Mark { chunk: self.current.get(), offset: self.offset.get() }
+    }
+
+    /// Drops everything allocated since `mark`. Takes `&mut` so nothing
+    /// handed out by `alloc` can still be alive.
+    #[inline]
+    pub fn rewind(&mut self, mark: Mark) {
+        self.current.set(mark.chunk);
+        self.offset.set(mark.offset);
+    }
+
+    /// Everything goes, chunks stay mapped for the next round.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.current.set(null_mut());
+        self.offset.set(0);
+    }
+}
+
+impl Default for Arena {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let mut chunk = self.first.get();
+        while !chunk.is_null() {
+            unsafe {
+                let next = (*chunk).next;
+                unmap_chunk(chunk as *mut u8, (*chunk).size);
+                chunk = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut arena = Arena::with_chunk_size(8192);
+        let start = arena.checkpoint();
+        let kept = arena.alloc(5u64) as *mut u64;
+        let mark = arena.checkpoint();
+
+        // Spills into a second chunk, rewinding comes back out of it
+        let first = arena.alloc_slice(&[1u32; 3000]).as_ptr();
+        let second = arena.alloc_slice(&[1u32; 3000]).as_ptr();
+        arena.rewind(mark);
+        assert_eq!(arena.alloc_slice(&[2u32; 3000]).as_ptr(), first);
+        assert_eq!(arena.alloc_slice(&[3u32; 3000]).as_ptr(), second); // Spare got reused
+        assert_eq!(unsafe { *kept }, 5);
+
+        arena.rewind(start);
+        assert_eq!(arena.alloc(7u64) as *mut u64, kept);
+        arena.reset();
+        assert_eq!(arena.alloc(9u64) as *mut u64, kept);
+        assert_eq!(arena.checkpoint(), Mark { chunk: arena.first.get(), offset: CHUNK_HEADER + 8 });
+    }
+
+    #[test]
+    fn test_alignment_and_big() {
+        let arena = Arena::with_chunk_size(8192);
+        arena.alloc(1u8);
+        let wide = arena.alloc_raw(Layout::from_size_align(64, 256).unwrap());
+        assert_eq!(wide as usize % 256, 0);
+
+        // Bigger than a chunk gets a chunk of its own size
+        let big = arena.alloc_raw(Layout::from_size_align(100_000, 4096).unwrap());
+        assert_eq!(big as usize % 4096, 0);
+        unsafe { big.write_bytes(1, 100_000) };
+    }
+}
@@ -0,0 +1,92 @@
+//! What `SharedAllocator` needs from whatever is doing the real work.
+//! Same shape as `Allocator`'s own methods, null on failure, so swapping
+//! backends is a type parameter and not a rewrite.
+
+use core::alloc::Layout;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::alloc::Allocator;
+use super::source::ChunkSource;
+
+pub trait Backend {
+    #[cfg_attr(feature = "track", track_caller)]
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Gives `ptr` back.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have come from this backend with `layout`, and
+    /// not been freed since.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// `GlobalAlloc::realloc` shape, null and `ptr` untouched on failure.
+    ///
+    /// # Safety
+    /// Same as `dealloc`, and `ptr` is gone unless this returns null.
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
+
+    /// Bytes handed out right now, rounding included.
+    fn used_bytes(&self) -> usize;
+
+    /// Like `realloc`, but `new` may ask for a different alignment.
+    ///
+    /// # Safety
+    /// Same as `realloc`, with `old` the layout `ptr` was allocated with.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn grow(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        if old.align() == new.align() {
+            return unsafe { self.realloc(ptr, old, new.size()) };
+        }
+        let new_ptr = self.allocate(new);
+        if new_ptr.is_null() {
+            return null_mut(); // Old block stays valid
+        }
+        unsafe {
+            copy_nonoverlapping(ptr, new_ptr, old.size().min(new.size()));
+            self.dealloc(ptr, old);
+        }
+        new_ptr
+    }
+
+    /// # Safety
+    /// Same as `grow`.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn shrink(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        unsafe { self.grow(ptr, old, new) } // Same dance, the copy is just shorter
+    }
+}
+
+impl<S: ChunkSource> Backend for Allocator<S> {
+    #[inline]
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        Allocator::allocate(self, layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { Allocator::dealloc(self, ptr, layout) }
+    }
+
+    #[inline]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { Allocator::realloc(self, ptr, layout, new_size) }
+    }
+
+    #[inline]
+    fn used_bytes(&self) -> usize {
+        Allocator::used_bytes(self)
+    }
+
+    #[inline]
+    unsafe fn grow(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        unsafe { Allocator::grow(self, ptr, old, new) }
+    }
+
+    #[inline]
+    unsafe fn shrink(&mut self, ptr: *mut u8, old: Layout, new: Layout) -> *mut u8 {
+        unsafe { Allocator::shrink(self, ptr, old, new) }
+    }
+}
@@ -0,0 +1,132 @@
+//! Boundary tags for the blocks living inside chunks.
+//! Every block starts with a 16-byte header holding its own size and the size
+//! of its left neighbour, so a freed block can find both neighbours and merge
+//! without a footer. Free blocks keep their list links in the payload.
+//!
+//! Chunk layout:
+//! `[ChunkHeader][block][block]...[bump frontier ... ][epilogue]`
+
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+pub(crate) const HEADER: usize = size_of::<BlockHeader>();
+pub(crate) const BLOCK_ALIGN: usize = 16;
+pub(crate) const MIN_BLOCK: usize = HEADER + size_of::<FreeLinks>().next_multiple_of(BLOCK_ALIGN);
+pub(crate) const CHUNK_HEADER: usize = size_of::<ChunkHeader>();
+
+// Low bit of the size, sizes are always multiples of 16
+const USED: usize = 1;
+
+#[repr(C, align(16))]
+pub(crate) struct BlockHeader {
+    size: usize,      // Whole block, header included, low bit = used
+    prev_size: usize, // Left neighbour, 0 if first in the chunk
+}
+
+// Lives in the payload of free blocks only
+#[repr(C)]
+pub(crate) struct FreeLinks {
+    pub(crate) next: *mut BlockHeader,
+    pub(crate) prev: *mut BlockHeader,
+}
+
+// Every chunk starts with one of these, so the allocator can find them all again
+#[repr(C, align(16))]
+pub(crate) struct ChunkHeader {
+    pub(crate) next: *mut ChunkHeader,
+    pub(crate) size: usize,
+}
+
+impl BlockHeader {
+    #[inline(always)]
+    pub(crate) unsafe fn write(at: *mut u8, size: usize, prev_size: usize, used: bool) -> *mut Self {
+        unsafe {
+            let block = at as *mut Self;
+            (*block).size = size | used as usize;
+            (*block).prev_size = prev_size;
+            block
+        }
+    }
+
+    /// Zero-size, always used, so nothing ever merges past the chunk end.
+    #[inline(always)]
+    pub(crate) unsafe fn write_epilogue(at: *mut u8) {
+        unsafe {
+            Self::write(at, 0, 0, true);
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn from_payload(ptr: *mut u8) -> *mut Self {
+        unsafe {
+            ptr.sub(HEADER) as *mut Self
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn payload(block: *mut Self) -> *mut u8 {
+        unsafe {
+            (block as *mut u8).add(HEADER)
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn size(block: *const Self) -> usize {
+        unsafe {
+            (*block).size & !USED
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn is_used(block: *const Self) -> bool {
+        unsafe {
+            (*block).size & USED != 0
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn set(block: *mut Self, size: usize, used: bool) {
+        unsafe {
+            (*block).size = size | used as usize;
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn set_prev_size(block: *mut Self, prev_size: usize) {
+        unsafe {
+            (*block).prev_size = prev_size;
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn next(block: *mut Self) -> *mut Self {
+        unsafe {
+            (block as *mut u8).add(Self::size(block)) as *mut Self
+        }
+    }
+
+    /// Left neighbour, or null if this is the first block in its chunk.
+    #[inline(always)]
+    pub(crate) unsafe fn prev(block: *mut Self) -> *mut Self {
+        unsafe {
+            match (*block).prev_size {
+                0 => null_mut(),
+                prev_size => (block as *mut u8).sub(prev_size) as *mut Self,
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn links(block: *mut Self) -> *mut FreeLinks {
+        unsafe {
+            Self::payload(block) as *mut FreeLinks
+        }
+    }
+}
+
+/// Block size for a payload, header included and rounded to `BLOCK_ALIGN`.
+#[inline(always)]
+pub(crate) const fn block_size(payload: usize) -> usize {
+    let size = (payload + HEADER + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1);
+    if size < MIN_BLOCK { MIN_BLOCK } else { size }
+}
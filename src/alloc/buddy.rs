@@ -0,0 +1,448 @@
+//! Binary buddy allocator, for when fragmentation has to stay bounded.
+//! Everything is a power of two, and every block has exactly one buddy it
+//! can merge with, found by flipping one bit of its offset. Splitting and
+//! merging walk at most `ORDERS` levels. Free blocks sit on one list per
+//! order, and each region keeps a bit per block per order saying "free",
+//! so checking a buddy is a bit test and not a list walk.
+//!
+//! Regions are mapped aligned to their own size, so a pointer finds its
+//! region with a mask. Region layout:
+//! `[Region][bitmap, order 0 first][...][blocks ...]`, the header and
+//! bitmap live in a block that's never free
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::alloc::{map_aligned, unmap_aligned, PAGE_SIZE};
+use super::backend::Backend;
+
+const MIN_SHIFT: usize = 5; // 32 bytes, the smallest block
+const REGION_SHIFT: usize = 22; // 4MB regions
+pub const ORDERS: usize = REGION_SHIFT - MIN_SHIFT + 1;
+pub const MIN_BLOCK: usize = 1 << MIN_SHIFT;
+pub const REGION_SIZE: usize = 1 << REGION_SHIFT;
+
+// One bit per block, every order, order 0 has the most blocks by far
+const BLOCKS: usize = REGION_SIZE >> MIN_SHIFT;
+const BITMAP_BYTES: usize = 2 * BLOCKS / 8;
+const META_ORDER: usize = order_of(size_of::<Region>() + BITMAP_BYTES);
+
+#[repr(C)]
+struct Region {
+    next: *mut Region,
+    _pad: usize, // Keeps the bitmap 16 aligned
+}
+
+// Lives in the block while it's free
+#[repr(C)]
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+#[inline(always)]
+const fn block_bytes(order: usize) -> usize {
+    1 << (order + MIN_SHIFT)
+}
+
+// Smallest order whose block holds `bytes`
+#[inline(always)]
+const fn order_of(bytes: usize) -> usize {
+    let bytes = if bytes < MIN_BLOCK { MIN_BLOCK } else { bytes };
+    (bytes.next_power_of_two().trailing_zeros() as usize) - MIN_SHIFT
+}
+
+// Bits for orders below `order`, 2N - 2N/2^order
+#[inline(always)]
+const fn bit_base(order: usize) -> usize {
+    2 * BLOCKS - ((2 * BLOCKS) >> order)
+}
+
+pub struct Buddy {
+    free: [*mut FreeBlock; ORDERS], // Free blocks per order, all regions mixed
+    regions: *mut Region,           // Newest first
+    region_count: usize,
+    used_bytes: usize,              // Blocks and direct mappings handed out
+}
+
+// Free lists and regions are all memory it mapped itself and owns outright
+unsafe impl Send for Buddy {}
+
+impl Buddy {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            free: [null_mut(); ORDERS],
+            regions: null_mut(),
+            region_count: 0,
+            used_bytes: 0,
+        }
+    }
+
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    #[inline]
+    pub fn region_count(&self) -> usize {
+        self.region_count
+    }
+
+    /// How many free blocks each order has, where fragmentation shows.
+    pub fn free_blocks(&self) -> [usize; ORDERS] {
+        let mut counts = [0; ORDERS];
+        for (order, count) in counts.iter_mut().enumerate() {
+            let mut block = self.free[order];
+            while !block.is_null() {
+                *count += 1;
+                block = unsafe { (*block).next };
+            }
+        }
+        counts
+    }
+
+    /// Rounds `layout` up to a power of two block. Anything bigger than half
+    /// a region, or aligned past one, gets its own mapping.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let need = layout.size().max(layout.align());
+        if Self::is_direct(need) {
+            let size = Self::direct_size(layout.size());
+            let ptr = unsafe { map_aligned(size, layout.align()) };
+            if !ptr.is_null() {
+                self.used_bytes += size;
+            }
+            return ptr;
+        }
+
+        let order = order_of(need);
+        let ptr = unsafe { self.take(order) };
+        if !ptr.is_null() {
+            self.used_bytes += block_bytes(order);
+        }
+        ptr
+    }
+
+    /// Gives the block back, merging with its buddy as far up as it goes.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have come from this heap with `layout`, and not
+    /// been freed since.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+        let need = layout.size().max(layout.align());
+        if Self::is_direct(need) {
+            let size = Self::direct_size(layout.size());
+            unsafe { unmap_aligned(ptr, size, layout.align()) };
+            self.used_bytes -= size;
+            return;
+        }
+
+        let order = order_of(need);
+        unsafe { self.give(ptr, order) };
+        self.used_bytes -= block_bytes(order);
+    }
+
+    /// Same block if the order doesn't change, split in place if it shrinks,
+    /// moved otherwise. Null and `ptr` untouched if there's no room.
+    ///
+    /// # Safety
+    /// Same as `dealloc`.
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new) => new,
+            Err(_) => return null_mut(), // Size overflows once aligned
+        };
+        if ptr.is_null() {
+            return self.allocate(new);
+        }
+
+        let (old_need, new_need) = (layout.size().max(layout.align()), new_size.max(layout.align()));
+        if !Self::is_direct(old_need) && !Self::is_direct(new_need) {
+            let (old_order, new_order) = (order_of(old_need), order_of(new_need));
+            if new_order == old_order {
+                return ptr;
+            }
+            if new_order < old_order {
+                // Upper halves go back, their buddies are the half we keep
+                for order in (new_order..old_order).rev() {
+                    unsafe { self.push(ptr.add(block_bytes(order)), order) };
+                }
+                self.used_bytes -= block_bytes(old_order) - block_bytes(new_order);
+                return ptr;
+            }
+        }
+
+        let new_ptr = self.allocate(new);
+        if new_ptr.is_null() {
+            return null_mut(); // Old block stays valid
+        }
+        unsafe {
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+
+    /// Unmaps every region with nothing live in it, returns the bytes.
+    pub fn trim(&mut self) -> usize {
+        let mut released = 0;
+        let mut link: *mut *mut Region = &mut self.regions;
+        unsafe {
+            while !(*link).is_null() {
+                let region = *link;
+                if !Self::is_idle(region) {
+                    link = &mut (*region).next;
+                    continue;
+                }
+                // Every block past the header is free at its biggest order
+                for order in META_ORDER..ORDERS - 1 {
+                    self.unlink((region as *mut u8).add(block_bytes(order)) as *mut FreeBlock, order);
+                }
+                *link = (*region).next;
+                unmap_aligned(region as *mut u8, REGION_SIZE, REGION_SIZE);
+                self.region_count -= 1;
+                released += REGION_SIZE;
+            }
+        }
+        released
+    }
+
+    #[inline(always)]
+    fn is_direct(need: usize) -> bool {
+        need > REGION_SIZE / 2
+    }
+
+    #[inline(always)]
+    fn direct_size(size: usize) -> usize {
+        (size.max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1) // Zero-size but huge align still maps
+    }
+
+    // Smallest free block that fits, split down to `order`
+    unsafe fn take(&mut self, order: usize) -> *mut u8 {
+        unsafe {
+            let mut from = order;
+            while from < ORDERS && self.free[from].is_null() {
+                from += 1;
+            }
+            if from == ORDERS {
+                if !self.new_region() {
+                    return null_mut(); // Allocation failed
+                }
+                // A fresh region has one of everything from META_ORDER up
+                from = order.max(META_ORDER);
+            }
+
+            let block = self.free[from] as *mut u8;
+            self.unlink(block as *mut FreeBlock, from);
+            while from > order {
+                from -= 1;
+                self.push(block.add(block_bytes(from)), from);
+            }
+            block
+        }
+    }
+
+    // Merges with the buddy while it's free, then lists whatever's left
+    unsafe fn give(&mut self, mut block: *mut u8, mut order: usize) {
+        unsafe {
+            let region = Self::region_of(block);
+            while order < ORDERS - 1 {
+                let offset = block as usize - region as usize;
+                let buddy = (region as *mut u8).add(offset ^ block_bytes(order));
+                if !Self::is_free(region, buddy, order) {
+                    break;
+                }
+                self.unlink(buddy as *mut FreeBlock, order);
+                block = block.min(buddy);
+                order += 1;
+            }
+            self.push(block, order);
+        }
+    }
+
+    // Header and bitmap take the first block, its buddies up the chain are free
+    #[cold]
+    unsafe fn new_region(&mut self) -> bool {
+        unsafe {
+            let region = map_aligned(REGION_SIZE, REGION_SIZE) as *mut Region;
+            if region.is_null() {
+                return false;
+            }
+            (*region).next = self.regions;
+            self.regions = region;
+            self.region_count += 1;
+            for order in META_ORDER..ORDERS - 1 {
+                self.push((region as *mut u8).add(block_bytes(order)), order);
+            }
+            true
+        }
+    }
+
+    // Nothing live means every buddy of the header block is free
+    unsafe fn is_idle(region: *mut Region) -> bool {
+        unsafe {
+            (META_ORDER..ORDERS - 1)
+                .all(|order| Self::is_free(region, (region as *mut u8).add(block_bytes(order)), order))
+        }
+    }
+
+    #[inline(always)]
+    fn region_of(ptr: *mut u8) -> *mut Region {
+        (ptr as usize & !(REGION_SIZE - 1)) as *mut Region
+    }
+
+    // Word and mask for `block`'s bit at `order`
+    #[inline(always)]
+    unsafe fn bit(region: *mut Region, block: *mut u8, order: usize) -> (*mut u64, u64) {
+        unsafe {
+            let index = (block as usize - region as usize) >> (order + MIN_SHIFT);
+            let bit = bit_base(order) + index;
+            let words = (region as *mut u8).add(size_of::<Region>()) as *mut u64;
+            (words.add(bit / 64), 1 << (bit % 64))
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn is_free(region: *mut Region, block: *mut u8, order: usize) -> bool {
+        unsafe {
+            let (word, mask) = Self::bit(region, block, order);
+            *word & mask != 0
+        }
+    }
+
+    #[inline]
+    unsafe fn push(&mut self, block: *mut u8, order: usize) {
+        unsafe {
+            let (word, mask) = Self::bit(Self::region_of(block), block, order);
+            *word |= mask;
+
+            let block = block as *mut FreeBlock;
+            (*block).prev = null_mut();
+            (*block).next = self.free[order];
+            if !self.free[order].is_null() {
+                (*self.free[order]).prev = block;
+            }
+            self.free[order] = block;
+        }
+    }
+
+    #[inline]
+    unsafe fn unlink(&mut self, block: *mut FreeBlock, order: usize) {
+        unsafe {
+            let (word, mask) = Self::bit(Self::region_of(block as *mut u8), block as *mut u8, order);
+            *word &= !mask;
+
+            let (next, prev) = ((*block).next, (*block).prev);
+            if prev.is_null() {
+                self.free[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+impl Default for Buddy {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Buddy {
+    #[inline]
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        Buddy::allocate(self, layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { Buddy::dealloc(self, ptr, layout) }
+    }
+
+    #[inline]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { Buddy::realloc(self, ptr, layout, new_size) }
+    }
+
+    #[inline]
+    fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+impl Drop for Buddy {
+    fn drop(&mut self) {
+        let mut region = self.regions;
+        while !region.is_null() {
+            unsafe {
+                let next = (*region).next;
+                unmap_aligned(region as *mut u8, REGION_SIZE, REGION_SIZE);
+                region = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let mut buddy = Buddy::new();
+        let first = buddy.allocate(layout(MIN_BLOCK));
+        unsafe { buddy.dealloc(first, layout(MIN_BLOCK)) };
+        let whole = buddy.free_blocks(); // A fresh region, merged all the way up
+
+        // Splitting down to order 0 leaves the other half of every level free
+        let a = buddy.allocate(layout(MIN_BLOCK));
+        assert_eq!(buddy.free_blocks()[0], 1);
+        let b = buddy.allocate(layout(20));
+        assert_eq!(a as usize ^ b as usize, MIN_BLOCK); // Each other's buddy
+        assert_eq!(buddy.free_blocks()[0], 0);
+
+        let big = buddy.allocate(layout(1000));
+        assert_eq!(big as usize % 1024, 0);
+        assert_eq!(buddy.used_bytes(), 2 * MIN_BLOCK + 1024);
+
+        unsafe { buddy.dealloc(a, layout(MIN_BLOCK)) };
+        assert_eq!(buddy.free_blocks()[0], 1); // Buddy's still out, no merge yet
+        unsafe {
+            buddy.dealloc(b, layout(20));
+            buddy.dealloc(big, layout(1000));
+        }
+        assert_eq!(buddy.free_blocks(), whole);
+        assert_eq!(buddy.used_bytes(), 0);
+        assert_eq!(buddy.trim(), REGION_SIZE);
+        assert_eq!(buddy.region_count(), 0);
+    }
+
+    #[test]
+    fn test_realloc_splits_in_place() {
+        let mut buddy = Buddy::new();
+        let ptr = buddy.allocate(layout(1024));
+        unsafe { ptr.write_bytes(7, 1024) };
+        assert_eq!(unsafe { buddy.realloc(ptr, layout(1024), 600) }, ptr); // Same order
+        assert_eq!(unsafe { buddy.realloc(ptr, layout(600), 100) }, ptr);
+        assert_eq!(buddy.used_bytes(), 128);
+
+        // The halves given back are whole blocks again
+        let again = buddy.allocate(layout(512));
+        assert_eq!(again as usize, ptr as usize + 512);
+        let grown = unsafe { buddy.realloc(ptr, layout(100), 300) };
+        assert_ne!(grown, ptr);
+        assert_eq!(unsafe { *grown.add(99) }, 7);
+    }
+}
@@ -0,0 +1,439 @@
+//! Heap that's allowed to move things, because nobody holds a pointer.
+//! Callers hold `Handle<T>`, an index into a relocation table, and go
+//! through the heap every time they want the value. That's what lets
+//! `defrag` slide live blocks down over the dead ones and patch the table,
+//! no guessing at what's live from the bytes. Values get moved with a
+//! memcpy, which every Rust type is fine with unless it's pinned.
+//!
+//! Heap layout:
+//! `[block][block]...[top ... ][end]`, each block `[BlockHead][value]`
+
+use core::marker::PhantomData;
+use core::mem::{align_of, needs_drop, size_of};
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+
+// Blocks only ever slide down by multiples of this, so it's the alignment cap
+const BLOCK_ALIGN: usize = 16;
+
+// No slot, the block is dead and waiting for defrag
+const DEAD: u32 = u32::MAX;
+
+// Heap ids, handed out on the first `alloc` so `new` can stay const
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[repr(C, align(16))]
+struct BlockHead {
+    size: u32,                        // Whole block, head included
+    slot: u32,                        // Table entry pointing here, DEAD once freed
+    drop: Option<unsafe fn(*mut u8)>, // Only for types that need it
+}
+
+const HEAD: usize = size_of::<BlockHead>();
+
+// Relocation table entry
+#[derive(Copy, Clone)]
+struct Entry {
+    value: *mut u8,  // Null while the entry is free
+    generation: u32, // Bumped on free, so stale handles miss
+    next_free: u32,  // Free entries chain through here
+}
+
+/// Reference into a `CompactHeap`, survives `defrag`. Copy it around, a stale
+/// one, or one from another heap, just gets `None` back.
+#[derive(Debug)]
+pub struct Handle<T> {
+    heap: usize, // Id of the heap it came from, never 0
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Handle<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        (self.heap, self.index, self.generation) == (other.heap, other.index, other.generation)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+pub struct CompactHeap {
+    base: *mut u8,   // One mapping, replaced wholesale when it grows
+    size: usize,     // Mapped bytes at base
+    top: usize,      // Bump offset, everything below is blocks
+    dead: usize,     // Bytes in dead blocks, what defrag can win back
+    table: *mut Entry,
+    table_len: u32,  // Entries ever handed out
+    table_cap: u32,
+    free_entry: u32, // Head of the free entry chain, DEAD if none
+    live: usize,
+    id: usize,       // 0 until the first alloc
+}
+
+impl CompactHeap {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            base: null_mut(),
+            size: 0,
+            top: 0,
+            dead: 0,
+            table: null_mut(),
+            table_len: 0,
+            table_cap: 0,
+            free_entry: DEAD,
+            live: 0,
+            id: 0,
+        }
+    }
+
+    /// Bytes in blocks, dead ones included.
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.top
+    }
+
+    /// Bytes `defrag` would get back right now.
+    #[inline]
+    pub fn dead_bytes(&self) -> usize {
+        self.dead
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Moves `value` into the heap, `None` if the kernel won't give us room
+    /// or `T` wants more than 16 byte alignment.
+    pub fn alloc<T>(&mut self, value: T) -> Option<Handle<T>> {
+        if align_of::<T>() > BLOCK_ALIGN {
+            return None; // Blocks slide by 16, anything stricter would break
+        }
+        if self.id == 0 {
+            self.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let block_size = (HEAD + size_of::<T>() + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1);
+        if block_size > u32::MAX as usize {
+            return None; // Sizes are u32 in the head
+        }
+        if self.top + block_size > self.size && !self.make_room(block_size) {
+            return None;
+        }
+        let index = self.take_entry()?;
+
+        unsafe {
+            let head = self.base.add(self.top) as *mut BlockHead;
+            head.write(BlockHead {
+                size: block_size as u32,
+                slot: index,
+                drop: if needs_drop::<T>() { Some(drop_value::<T>) } else { None },
+            });
+            let value_ptr = (head as *mut u8).add(HEAD);
+            (value_ptr as *mut T).write(value);
+
+            let entry = &mut *self.table.add(index as usize);
+            entry.value = value_ptr;
+            self.top += block_size;
+            self.live += 1;
+            Some(Handle { heap: self.id, index, generation: entry.generation, _marker: PhantomData })
+        }
+    }
+
+    #[inline]
+    pub fn get<T>(&self, handle: Handle<T>) -> Option<&T> {
+        self.resolve(handle).map(|ptr| unsafe { &*(ptr as *const T) })
+    }
+
+    /// Don't hold on to it across an `alloc`, that can move everything.
+    /// The borrow checker agrees.
+    #[inline]
+    pub fn get_mut<T>(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        self.resolve(handle).map(|ptr| unsafe { &mut *(ptr as *mut T) })
+    }
+
+    /// Moves the value back out, the block stays dead until `defrag`.
+    pub fn free<T>(&mut self, handle: Handle<T>) -> Option<T> {
+        let ptr = self.resolve(handle)?;
+        unsafe {
+            let head = ptr.sub(HEAD) as *mut BlockHead;
+            (*head).slot = DEAD;
+            self.dead += (*head).size as usize;
+            self.release_entry(handle.index);
+            self.live -= 1;
+            Some((ptr as *mut T).read())
+        }
+    }
+
+    /// Slides every live block down over the dead ones, patches the table,
+    /// and returns how many bytes that freed up at the top.
+    pub fn defrag(&mut self) -> usize {
+        if self.dead == 0 {
+            return 0;
+        }
+
+        let (mut read, mut write) = (0, 0);
+        unsafe {
+            while read < self.top {
+                let head = self.base.add(read) as *mut BlockHead;
+                let size = (*head).size as usize;
+                let slot = (*head).slot;
+                if slot != DEAD {
+                    if write != read {
+                        ptr::copy(self.base.add(read), self.base.add(write), size);
+                    }
+                    (*self.table.add(slot as usize)).value = self.base.add(write + HEAD);
+                    write += size;
+                }
+                read += size;
+            }
+        }
+
+        let reclaimed = self.top - write;
+        self.top = write;
+        self.dead = 0;
+        reclaimed
+    }
+
+    /// Drops everything, mappings stay for the next round. Every handle
+    /// out there goes stale.
+    pub fn clear(&mut self) {
+        self.drop_live();
+        unsafe {
+            for i in 0..self.table_len {
+                let entry = &mut *self.table.add(i as usize);
+                if !entry.value.is_null() {
+                    entry.value = null_mut();
+                    entry.generation = entry.generation.wrapping_add(1);
+                }
+                entry.next_free = if i + 1 < self.table_len { i + 1 } else { DEAD };
+            }
+        }
+        self.free_entry = if self.table_len > 0 { 0 } else { DEAD };
+        self.top = 0;
+        self.dead = 0;
+        self.live = 0;
+    }
+
+    #[inline]
+    fn resolve<T>(&self, handle: Handle<T>) -> Option<*mut u8> {
+        if handle.heap != self.id || handle.index >= self.table_len {
+            return None; // Some other heap's
+        }
+        let entry = unsafe { *self.table.add(handle.index as usize) };
+        if entry.value.is_null() || entry.generation != handle.generation {
+            return None; // Freed, or freed and handed out again
+        }
+        Some(entry.value)
+    }
+
+    // Defrag if that wins enough back, otherwise move into a bigger mapping
+    #[cold]
+    fn make_room(&mut self, needed: usize) -> bool {
+        if self.top - self.dead + needed <= self.size {
+            self.defrag();
+            return true;
+        }
+
+        let size = ((self.top - self.dead + needed) * 2).max(64 << 10);
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let base = unsafe { map_chunk(size) };
+        if base.is_null() {
+            return false;
+        }
+
+        // Copy live blocks across packed, same walk as defrag
+        let (mut read, mut write) = (0, 0);
+        unsafe {
+            while read < self.top {
+                let head = self.base.add(read) as *mut BlockHead;
+                let block = (*head).size as usize;
+                if (*head).slot != DEAD {
+                    ptr::copy_nonoverlapping(self.base.add(read), base.add(write), block);
+                    (*self.table.add((*head).slot as usize)).value = base.add(write + HEAD);
+                    write += block;
+                }
+                read += block;
+            }
+            if !self.base.is_null() {
+                unmap_chunk(self.base, self.size);
+            }
+        }
+
+        self.base = base;
+        self.size = size;
+        self.top = write;
+        self.dead = 0;
+        true
+    }
+
+    fn take_entry(&mut self) -> Option<u32> {
+        if self.free_entry != DEAD {
+            let index = self.free_entry;
+            self.free_entry = unsafe { (*self.table.add(index as usize)).next_free };
+            return Some(index);
+        }
+
+        if self.table_len == self.table_cap && !self.grow_table() {
+            return None;
+        }
+        let index = self.table_len;
+        unsafe {
+            self.table.add(index as usize).write(Entry {
+                value: null_mut(),
+                generation: 0,
+                next_free: DEAD,
+            })
+        };
+        self.table_len += 1;
+        Some(index)
+    }
+
+    #[inline]
+    fn release_entry(&mut self, index: u32) {
+        let entry = unsafe { &mut *self.table.add(index as usize) };
+        entry.value = null_mut();
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.next_free = self.free_entry;
+        self.free_entry = index;
+    }
+
+    // Doubles the table, starting at a page
+    #[cold]
+    fn grow_table(&mut self) -> bool {
+        if self.table_cap >= DEAD / 2 {
+            return false; // DEAD has to stay out of reach
+        }
+        let bytes = (self.table_cap as usize * size_of::<Entry>() * 2).max(PAGE_SIZE);
+        let table = unsafe { map_chunk(bytes) } as *mut Entry;
+        if table.is_null() {
+            return false;
+        }
+        unsafe {
+            if !self.table.is_null() {
+                ptr::copy_nonoverlapping(self.table, table, self.table_len as usize);
+                unmap_chunk(self.table as *mut u8, self.table_cap as usize * size_of::<Entry>());
+            }
+        }
+        self.table = table;
+        self.table_cap = (bytes / size_of::<Entry>()) as u32;
+        true
+    }
+
+    fn drop_live(&mut self) {
+        let mut offset = 0;
+        while offset < self.top {
+            unsafe {
+                let head = self.base.add(offset) as *mut BlockHead;
+                if (*head).slot != DEAD {
+                    if let Some(drop) = (*head).drop {
+                        drop((head as *mut u8).add(HEAD));
+                    }
+                }
+                offset += (*head).size as usize;
+            }
+        }
+    }
+}
+
+impl Default for CompactHeap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for CompactHeap {
+    fn drop(&mut self) {
+        self.drop_live();
+        unsafe {
+            if !self.base.is_null() {
+                unmap_chunk(self.base, self.size);
+            }
+            if !self.table.is_null() {
+                unmap_chunk(self.table as *mut u8, self.table_cap as usize * size_of::<Entry>());
+            }
+        }
+    }
+}
+
+unsafe fn drop_value<T>(ptr: *mut u8) {
+    unsafe {
+        ptr::drop_in_place(ptr as *mut T);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(32))]
+    struct Wide;
+
+    #[test]
+    fn test_defrag_moves_live_values() {
+        let mut heap = CompactHeap::new();
+        let handles: [Handle<[u64; 4]>; 8] =
+            core::array::from_fn(|i| heap.alloc([i as u64; 4]).unwrap());
+        let before = heap.get(handles[7]).unwrap() as *const [u64; 4];
+        let used = heap.used_bytes();
+
+        for i in (0..8).step_by(2) {
+            assert_eq!(heap.free(handles[i]), Some([i as u64; 4]));
+        }
+        assert_eq!(heap.dead_bytes(), used / 2);
+        assert_eq!(heap.defrag(), used / 2);
+        assert_eq!(heap.dead_bytes(), 0);
+        assert_eq!(heap.defrag(), 0); // Nothing left to slide
+
+        // Survivors moved down and their handles followed
+        assert_ne!(heap.get(handles[7]).unwrap() as *const [u64; 4], before);
+        for i in (1..8).step_by(2) {
+            assert_eq!(heap.get(handles[i]), Some(&[i as u64; 4]));
+        }
+        heap.get_mut(handles[3]).unwrap()[0] = 33;
+        assert_eq!(heap.get(handles[3]).unwrap()[0], 33);
+
+        // The dead ones stay dead, even once their slot is taken again
+        let reused = heap.alloc([9u64; 4]).unwrap();
+        assert_eq!(heap.get(handles[6]), None);
+        assert_eq!(heap.get(reused), Some(&[9; 4]));
+        assert_eq!(heap.len(), 5);
+    }
+
+    #[test]
+    fn test_foreign_handles() {
+        let mut heap = CompactHeap::new();
+        let mut other = CompactHeap::new();
+        let mine = heap.alloc(1u64).unwrap();
+        let theirs = other.alloc(2u64).unwrap();
+
+        // Same index and generation, different heap
+        assert_eq!(heap.get(theirs), None);
+        assert_eq!(heap.get_mut(theirs), None);
+        assert_eq!(heap.free(theirs), None);
+        assert_eq!(heap.get(mine), Some(&1));
+        assert_eq!(other.free(theirs), Some(2));
+
+        assert!(heap.alloc(Wide).is_none());
+        assert_eq!(heap.len(), 1);
+    }
+}
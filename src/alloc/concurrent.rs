@@ -0,0 +1,353 @@
+//! Bump allocation any number of threads can share without a lock.
+//! The offset inside the current chunk moves with one `fetch_add`, the lock
+//! only comes out when a chunk runs dry and somebody has to map the next.
+//! Threads that allocate a lot take a `LocalCache`, a slice of chunk that
+//! bumps with no atomics at all until it's used up.
+//!
+//! Nothing is freed one at a time, it's a bump. Memory comes back on
+//! `reset` or drop, which take `&mut` so no thread can still be in there.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use super::alloc::PAGE_SIZE;
+use super::source::{ChunkSource, OsChunks};
+
+// Every bump is a multiple of this, so anything aligned up to it needs no padding
+const MIN_ALIGN: usize = 16;
+const HEADER: usize = (size_of::<Chunk>() + MIN_ALIGN - 1) & !(MIN_ALIGN - 1);
+
+/// What a `LocalCache` takes from the shared chunk in one go.
+pub const LOCAL_SLICE: usize = 16 << 10;
+
+#[repr(C)]
+struct Chunk {
+    next: *mut Chunk,
+    size: usize,         // Mapped bytes, header included
+    align: usize,        // What it was mapped with, unmap wants it back
+    offset: AtomicUsize, // Bumped past size once it's full, nobody minds
+}
+
+pub struct ConcurrentBump<S: ChunkSource = OsChunks> {
+    current: AtomicPtr<Chunk>,      // Where the fetch_add happens, null before the first alloc
+    chunks: UnsafeCell<*mut Chunk>, // Every chunk and big mapping, newest first, lock held
+    source: UnsafeCell<S>,          // Lock held too
+    locked: AtomicBool,             // Only for mapping, never for bumping
+    used_bytes: AtomicUsize,        // Padding and local slices included
+    chunk_count: AtomicUsize,
+    chunk_size: usize,
+}
+
+// Shared state is atomics, the rest is behind `locked`
+unsafe impl<S: ChunkSource + Send> Send for ConcurrentBump<S> {}
+unsafe impl<S: ChunkSource + Send> Sync for ConcurrentBump<S> {}
+
+impl ConcurrentBump {
+    #[inline]
+    pub const fn new() -> Self {
+        Self::with_source(OsChunks)
+    }
+}
+
+impl Default for ConcurrentBump {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: ChunkSource> ConcurrentBump<S> {
+    #[inline]
+    pub const fn with_source(source: S) -> Self {
+        Self {
+            current: AtomicPtr::new(null_mut()),
+            chunks: UnsafeCell::new(null_mut()),
+            source: UnsafeCell::new(source),
+            locked: AtomicBool::new(false),
+            used_bytes: AtomicUsize::new(0),
+            chunk_count: AtomicUsize::new(0),
+            chunk_size: 1 << 20, // 1MB chunks, same as Allocator
+        }
+    }
+
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count.load(Ordering::Relaxed)
+    }
+
+    /// Bytes for `layout` from whichever thread, null if the source is dry.
+    /// Anything over a quarter chunk, or aligned past a page, gets its own
+    /// mapping under the lock.
+    pub fn allocate(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = (layout.size(), layout.align());
+        if size > self.chunk_size >> 2 || align > PAGE_SIZE {
+            return self.allocate_large(size, align);
+        }
+
+        // Over-ask by the padding the alignment could need, no CAS loop then
+        let padding = align.saturating_sub(MIN_ALIGN);
+        let reserve = (size + padding + MIN_ALIGN - 1) & !(MIN_ALIGN - 1);
+        loop {
+            let chunk = self.current.load(Ordering::Acquire);
+            if !chunk.is_null() {
+                let ptr = unsafe { Self::bump(chunk, reserve, align) };
+                if !ptr.is_null() {
+                    self.used_bytes.fetch_add(reserve, Ordering::Relaxed);
+                    return ptr;
+                }
+            }
+            if !self.refill(chunk) {
+                return null_mut(); // Allocation failed
+            }
+        }
+    }
+
+    /// A bump-only cache for one thread, refilled `LOCAL_SLICE` at a time.
+    #[inline]
+    pub fn local(&self) -> LocalCache<'_, S> {
+        LocalCache { shared: self, cursor: null_mut(), limit: null_mut() }
+    }
+
+    /// Unmaps everything, every pointer handed out is dead after this.
+    pub fn reset(&mut self) {
+        unsafe {
+            let mut chunk = *self.chunks.get_mut();
+            while !chunk.is_null() {
+                let next = (*chunk).next;
+                self.source.get_mut().unmap(chunk as *mut u8, (*chunk).size, (*chunk).align);
+                chunk = next;
+            }
+        }
+        *self.chunks.get_mut() = null_mut();
+        *self.current.get_mut() = null_mut();
+        *self.used_bytes.get_mut() = 0;
+        *self.chunk_count.get_mut() = 0;
+    }
+
+    // Claims `reserve` bytes of `chunk`, null if that ran past the end
+    #[inline(always)]
+    unsafe fn bump(chunk: *mut Chunk, reserve: usize, align: usize) -> *mut u8 {
+        unsafe {
+            let offset = (*chunk).offset.fetch_add(reserve, Ordering::Relaxed);
+            let start = (chunk as usize + offset + align - 1) & !(align - 1);
+            if offset + reserve > (*chunk).size {
+                return null_mut();
+            }
+            start as *mut u8
+        }
+    }
+
+    // Maps the next chunk unless somebody beat us to it while we waited
+    #[cold]
+    fn refill(&self, seen: *mut Chunk) -> bool {
+        self.lock();
+        let mapped = self.current.load(Ordering::Acquire) != seen || unsafe {
+            let chunk = self.map(self.chunk_size, PAGE_SIZE);
+            if !chunk.is_null() {
+                (*chunk).offset = AtomicUsize::new(HEADER);
+                self.current.store(chunk, Ordering::Release);
+            }
+            !chunk.is_null()
+        };
+        self.unlock();
+        mapped
+    }
+
+    #[cold]
+    fn allocate_large(&self, size: usize, align: usize) -> *mut u8 {
+        let lead = HEADER.max(align); // Header goes in front, the payload stays aligned
+        let mapped = (lead + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        self.lock();
+        let chunk = unsafe { self.map(mapped, align.max(PAGE_SIZE)) };
+        self.unlock();
+        if chunk.is_null() {
+            return null_mut(); // Allocation failed
+        }
+        unsafe { (*chunk).offset = AtomicUsize::new(mapped) }; // Full, never current
+        self.used_bytes.fetch_add(mapped, Ordering::Relaxed);
+        unsafe { (chunk as *mut u8).add(lead) }
+    }
+
+    // Maps and lists a chunk, lock held
+    unsafe fn map(&self, size: usize, align: usize) -> *mut Chunk {
+        unsafe {
+            let chunk = (*self.source.get()).map(size, align) as *mut Chunk;
+            if chunk.is_null() {
+                return null_mut();
+            }
+            (*chunk).next = *self.chunks.get();
+            (*chunk).size = size;
+            (*chunk).align = align;
+            *self.chunks.get() = chunk;
+            self.chunk_count.fetch_add(1, Ordering::Relaxed);
+            chunk
+        }
+    }
+
+    #[inline]
+    fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+// Bump allocators don't free, dealloc is a no-op and realloc always moves
+unsafe impl<S: ChunkSource> GlobalAlloc for ConcurrentBump<S> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+impl<S: ChunkSource> Drop for ConcurrentBump<S> {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+/// One thread's slice of a `ConcurrentBump`. Not `Send`, keep one per
+/// thread. Whatever's left of the slice when it's dropped is wasted.
+pub struct LocalCache<'a, S: ChunkSource = OsChunks> {
+    shared: &'a ConcurrentBump<S>,
+    cursor: *mut u8, // Next free byte of the slice
+    limit: *mut u8,  // End of the slice
+}
+
+impl<S: ChunkSource> LocalCache<'_, S> {
+    /// Plain bump inside the slice, the shared one only gets touched on refill.
+    #[inline]
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = (layout.size(), layout.align());
+        let start = (self.cursor as usize + align - 1) & !(align - 1);
+        if !self.cursor.is_null() && start + size <= self.limit as usize {
+            self.cursor = (start + size) as *mut u8;
+            return start as *mut u8;
+        }
+        self.refill(layout)
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.limit as usize - self.cursor as usize
+    }
+
+    // Big requests skip the slice, there'd be nothing left of it
+    #[cold]
+    fn refill(&mut self, layout: Layout) -> *mut u8 {
+        if layout.size() > LOCAL_SLICE >> 2 || layout.align() > MIN_ALIGN {
+            return self.shared.allocate(layout);
+        }
+        let slice_layout = unsafe { Layout::from_size_align_unchecked(LOCAL_SLICE, MIN_ALIGN) };
+        let slice = self.shared.allocate(slice_layout);
+        if slice.is_null() {
+            return null_mut(); // Allocation failed
+        }
+        self.cursor = slice;
+        self.limit = unsafe { slice.add(LOCAL_SLICE) };
+        self.allocate(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::thread;
+    use std::vec::Vec;
+
+    const PER_THREAD: usize = 10_000;
+    const SIZE: usize = 48;
+
+    // Every byte of every block says who owns it, then nobody may have overlapped
+    fn check(mut blocks: Vec<(usize, u8)>) {
+        for &(ptr, id) in &blocks {
+            let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, SIZE) };
+            assert!(bytes.iter().all(|&byte| byte == id));
+        }
+        blocks.sort_unstable();
+        assert!(blocks.windows(2).all(|pair| pair[1].0 - pair[0].0 >= SIZE));
+    }
+
+    #[test]
+    fn test_threads_bump_apart() {
+        let bump = ConcurrentBump::new();
+        let blocks: Vec<(usize, u8)> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4u8)
+                .map(|id| {
+                    let bump = &bump;
+                    scope.spawn(move || {
+                        let align = 16 << (id % 3); // 16, 32 and 64
+                        let layout = Layout::from_size_align(SIZE, align).unwrap();
+                        (0..PER_THREAD)
+                            .map(|_| {
+                                let ptr = bump.allocate(layout);
+                                assert_eq!(ptr as usize % align, 0);
+                                unsafe { ptr.write_bytes(id, SIZE) };
+                                (ptr as usize, id)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+        });
+        assert!(bump.used_bytes() >= 4 * PER_THREAD * SIZE);
+        assert!(bump.chunk_count() > 1);
+        check(blocks);
+    }
+
+    #[test]
+    fn test_local_caches() {
+        let bump = ConcurrentBump::new();
+        let blocks: Vec<(usize, u8)> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4u8)
+                .map(|id| {
+                    let bump = &bump;
+                    scope.spawn(move || {
+                        let mut local = bump.local();
+                        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+                        let mut blocks = Vec::new();
+                        for i in 0..PER_THREAD {
+                            let ptr = local.allocate(layout);
+                            unsafe { ptr.write_bytes(id, SIZE) };
+                            blocks.push((ptr as usize, id));
+                            if i % 1000 == 0 {
+                                // Too big for the slice, goes straight to the shared one
+                                let big = local.allocate(Layout::from_size_align(8192, 8).unwrap());
+                                assert!(!big.is_null());
+                            }
+                        }
+                        blocks
+                    })
+                })
+                .collect();
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+        });
+        check(blocks);
+    }
+}
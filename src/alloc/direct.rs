@@ -0,0 +1,93 @@
+//! Every mapping `Allocator` made for one big allocation, outside any chunk.
+//! The chunks keep their own list in their headers, these have no header to
+//! spare, so `clear` and `Drop` find them here. Like the tracker's table it
+//! lives in its own mapping, and frees scan from the back.
+
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+
+/// One `ChunkSource::map` call, exactly what goes back to `unmap`.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct DirectMap {
+    pub(crate) ptr: *mut u8,
+    pub(crate) size: usize,
+    pub(crate) align: usize,
+}
+
+pub(crate) struct DirectMaps {
+    maps: *mut DirectMap,
+    len: usize,
+    cap: usize,
+}
+
+impl DirectMaps {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self { maps: null_mut(), len: 0, cap: 0 }
+    }
+
+    /// False if the table couldn't grow, the mapping isn't on it then.
+    pub(crate) fn push(&mut self, ptr: *mut u8, size: usize, align: usize) -> bool {
+        if self.len == self.cap && !self.grow() {
+            return false;
+        }
+        unsafe { self.maps.add(self.len).write(DirectMap { ptr, size, align }) };
+        self.len += 1;
+        true
+    }
+
+    pub(crate) fn remove(&mut self, ptr: *mut u8) {
+        let maps = self.maps();
+        if let Some(i) = maps.iter().rposition(|map| map.ptr == ptr) {
+            unsafe { *self.maps.add(i) = *self.maps.add(self.len - 1) };
+            self.len -= 1;
+        }
+    }
+
+    /// Takes the newest one off, for emptying the lot.
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<DirectMap> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { *self.maps.add(self.len) })
+    }
+
+    #[inline]
+    fn maps(&self) -> &[DirectMap] {
+        match self.len {
+            0 => &[],
+            len => unsafe { core::slice::from_raw_parts(self.maps, len) },
+        }
+    }
+
+    // Doubles the table, starting at a page
+    #[cold]
+    fn grow(&mut self) -> bool {
+        let bytes = (self.cap * size_of::<DirectMap>() * 2).max(PAGE_SIZE);
+        let maps = unsafe { map_chunk(bytes) } as *mut DirectMap;
+        if maps.is_null() {
+            return false;
+        }
+        unsafe {
+            if !self.maps.is_null() {
+                copy_nonoverlapping(self.maps, maps, self.len);
+                unmap_chunk(self.maps as *mut u8, self.cap * size_of::<DirectMap>());
+            }
+        }
+        self.maps = maps;
+        self.cap = bytes / size_of::<DirectMap>();
+        true
+    }
+}
+
+impl Drop for DirectMaps {
+    fn drop(&mut self) {
+        if !self.maps.is_null() {
+            unsafe { unmap_chunk(self.maps as *mut u8, self.cap * size_of::<DirectMap>()) };
+        }
+    }
+}
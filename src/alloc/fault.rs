@@ -0,0 +1,94 @@
+//! Allocations that fail on purpose, so the null and explode paths get run
+//! for once. Every policy is deterministic, the random one included, so a
+//! failing test fails the same way twice. Off by default, and `Never` costs
+//! one compare in `allocate`.
+
+/// Which allocations get turned into a fake OOM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultPolicy {
+    Never,
+    /// Only the Nth allocation after the policy is set, counting from 1.
+    Nth(u64),
+    /// Everything over this many bytes.
+    AboveSize(usize),
+    /// Roughly one in `one_in`, same seed, same failures.
+    Random { seed: u64, one_in: u32 },
+    /// Whatever would take the bytes handed out past the budget. A running
+    /// total since the policy was set, frees don't give any of it back, so
+    /// it's a budget for the whole test and not a cap on what's live.
+    /// `Allocator::set_limits` is the cap.
+    AfterBytes(usize),
+}
+
+pub(crate) struct FaultInjector {
+    policy: FaultPolicy,
+    count: u64,    // Allocations seen since the policy was set
+    bytes: usize,  // Bytes that went through, for AfterBytes, frees ignored
+    rng: u64,      // xorshift state, never zero
+    injected: u64, // Faults handed out, across policies
+}
+
+impl FaultInjector {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self { policy: FaultPolicy::Never, count: 0, bytes: 0, rng: 1, injected: 0 }
+    }
+
+    /// Swaps the policy and starts its counters over.
+    #[inline]
+    pub(crate) fn set(&mut self, policy: FaultPolicy) {
+        self.policy = policy;
+        self.count = 0;
+        self.bytes = 0;
+        if let FaultPolicy::Random { seed, .. } = policy {
+            self.rng = if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed }; // Zero sticks forever
+        }
+    }
+
+    #[inline]
+    pub(crate) fn policy(&self) -> FaultPolicy {
+        self.policy
+    }
+
+    #[inline]
+    pub(crate) fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    /// Asked once per allocation, true means pretend we're out.
+    #[inline(always)]
+    pub(crate) fn should_fail(&mut self, size: usize) -> bool {
+        if self.policy == FaultPolicy::Never {
+            return false;
+        }
+        self.roll(size)
+    }
+
+    #[cold]
+    fn roll(&mut self, size: usize) -> bool {
+        self.count += 1;
+        let fail = match self.policy {
+            FaultPolicy::Never => false,
+            FaultPolicy::Nth(n) => self.count == n,
+            FaultPolicy::AboveSize(limit) => size > limit,
+            FaultPolicy::Random { one_in, .. } => one_in != 0 && self.next() % one_in as u64 == 0,
+            FaultPolicy::AfterBytes(budget) => size > budget.saturating_sub(self.bytes),
+        };
+        if fail {
+            self.injected += 1;
+        } else {
+            self.bytes = self.bytes.saturating_add(size);
+        }
+        fail
+    }
+
+    #[inline]
+    fn next(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+}
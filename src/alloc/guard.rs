@@ -0,0 +1,142 @@
+//! Guard-page debug mode, for when something scribbles and you want the
+//! faulting instruction instead of a corrupted heap three calls later.
+//! Every allocation gets its own pages with a `PROT_NONE` page on each side,
+//! pushed up against the one you care about. Freed pages are dropped and
+//! protected, never unmapped, so a use-after-free faults too. That burns
+//! address space on purpose, it's a debug mode.
+
+use core::ptr::null_mut;
+
+use libc::{madvise, mprotect, MADV_DONTNEED, PROT_NONE};
+
+use super::alloc::{map_chunk, PAGE_SIZE};
+
+/// Which edge the allocation is pushed against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuardMode {
+    /// Ends right at the trailing guard page, overruns fault immediately.
+    /// Up to `align - 1` bytes of slack can hide at the end for odd sizes.
+    Overrun,
+    /// Starts right after the leading guard page, underruns fault immediately.
+    Underrun,
+}
+
+/// Readable bytes behind a guarded allocation, what gets charged.
+#[inline(always)]
+pub(crate) const fn data_len(size: usize) -> usize {
+    let size = if size == 0 { 1 } else { size };
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Whole mapping behind one, both guard pages and alignment slop included.
+/// None of it goes back on free.
+#[inline(always)]
+pub(crate) const fn mapped_len(size: usize, align: usize) -> usize {
+    PAGE_SIZE + data_len(size) + PAGE_SIZE + align.saturating_sub(PAGE_SIZE)
+}
+
+pub(crate) unsafe fn alloc(size: usize, align: usize, mode: GuardMode) -> *mut u8 {
+    unsafe {
+        let data = data_len(size);
+        let total = mapped_len(size, align);
+
+        let base = map_chunk(total);
+        if base.is_null() {
+            return null_mut();
+        }
+
+        // Everything that isn't data gets fenced off, leading slop included
+        let start = (base as usize + PAGE_SIZE + align.max(PAGE_SIZE) - 1) & !(align.max(PAGE_SIZE) - 1);
+        let end = start + data;
+        mprotect(base as *mut libc::c_void, start - base as usize, PROT_NONE);
+        mprotect(end as *mut libc::c_void, base as usize + total - end, PROT_NONE);
+
+        match mode {
+            GuardMode::Overrun => ((end - size) & !(align - 1)) as *mut u8,
+            GuardMode::Underrun => start as *mut u8,
+        }
+    }
+}
+
+// Pages go back to the kernel, the addresses stay poisoned
+pub(crate) unsafe fn free(ptr: *mut u8, size: usize) {
+    unsafe {
+        let start = (ptr as usize & !(PAGE_SIZE - 1)) as *mut libc::c_void;
+        let data = data_len(size);
+        madvise(start, data, MADV_DONTNEED);
+        mprotect(start, data, PROT_NONE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::alloc::Allocator;
+    use core::alloc::Layout;
+    use std::string::String;
+
+    // Permissions of whatever mapping holds `addr`, "---p" is PROT_NONE
+    fn perms(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let (lo, hi) = fields.next().unwrap().split_once('-').unwrap();
+            let (lo, hi) = (usize::from_str_radix(lo, 16).unwrap(), usize::from_str_radix(hi, 16).unwrap());
+            if (lo..hi).contains(&addr) {
+                return fields.next().unwrap().into();
+            }
+        }
+        panic!("{:#x} isn't mapped", addr);
+    }
+
+    #[test]
+    fn test_overrun_ends_at_guard() {
+        let mut heap = Allocator::guarded(GuardMode::Overrun);
+        // Sizes that are a multiple of the alignment, the rest get some slack
+        for (size, align) in [(1, 1), (100, 1), (96, 8), (PAGE_SIZE, 8), (5000, 8), (2 * PAGE_SIZE, 2 * PAGE_SIZE)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = heap.allocate(layout) as usize;
+            assert_eq!(ptr % align, 0);
+            assert_eq!((ptr + size) % PAGE_SIZE, 0, "{:?}", layout); // Not a byte of slack
+            assert_eq!(perms(ptr + size - 1), "rw-p");
+            assert_eq!(perms(ptr + size), "---p");
+            unsafe { heap.dealloc(ptr as *mut u8, layout) };
+        }
+    }
+
+    #[test]
+    fn test_underrun_starts_after_guard() {
+        let mut heap = Allocator::guarded(GuardMode::Underrun);
+        for (size, align) in [(1, 1), (100, 8), (5000, 8), (64, 2 * PAGE_SIZE)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = heap.allocate(layout) as usize;
+            assert_eq!(ptr % PAGE_SIZE.max(align), 0, "{:?}", layout);
+            assert_eq!(perms(ptr - 1), "---p");
+            assert_eq!(perms(ptr), "rw-p");
+            unsafe { heap.dealloc(ptr as *mut u8, layout) };
+        }
+    }
+
+    #[test]
+    fn test_freed_pages_stay_protected() {
+        let mut heap = Allocator::guarded(GuardMode::Overrun);
+        let layouts = [(100, 8), (5000, 8), (64, 2 * PAGE_SIZE)]
+            .map(|(size, align)| Layout::from_size_align(size, align).unwrap());
+        let ptrs = layouts.map(|layout| heap.allocate(layout));
+        for (ptr, layout) in ptrs.into_iter().zip(layouts) {
+            unsafe {
+                ptr.write_bytes(0xa5, layout.size());
+                heap.dealloc(ptr, layout);
+            }
+            assert_eq!(perms(ptr as usize), "---p"); // Use after free faults
+        }
+
+        let stats = heap.stats();
+        assert_eq!((stats.current_bytes, stats.direct_count), (0, 0));
+        let burnt: usize = layouts.iter().map(|l| mapped_len(l.size(), l.align())).sum();
+        assert_eq!(stats.guard_bytes, burnt);
+        assert_eq!(burnt, (3 + 4 + 4) * PAGE_SIZE);
+    }
+}
@@ -0,0 +1,294 @@
+//! Which cache lines of the heap are in use, one bit each.
+//! `Allocator::memory_map` walks every chunk's boundary tags and snapshots
+//! them into a bitmap, so it costs nothing until somebody asks. A line is
+//! used if any byte of a used block lands on it, slabs count whole.
+//! Direct mappings aren't in any chunk and don't show up.
+//!
+//! The bitmap lives in its own mapping, like the `track` table, so it can be
+//! taken from an OOM handler and written out as an ASCII heat map or JSON.
+
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+use super::block::{BlockHeader, ChunkHeader, CHUNK_HEADER};
+
+// Darker is fuller, an all-free stretch stays blank
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+// One per chunk, the bits start at `word` and each row starts a fresh one
+#[derive(Copy, Clone)]
+struct Row {
+    base: usize,
+    size: usize,
+    word: usize,
+}
+
+pub struct MemoryMap {
+    rows: *mut Row,  // Same order as the chunk list, newest first
+    words: *mut u64, // Bit n of a row is its n'th line
+    row_count: usize,
+    mapped: usize, // Bytes of our own mapping, 0 if there isn't one
+    line_size: usize,
+}
+
+impl MemoryMap {
+    #[inline]
+    pub const fn new() -> Self {
+        Self { rows: null_mut(), words: null_mut(), row_count: 0, mapped: 0, line_size: 64 }
+    }
+
+    /// Snapshots `chunks`, `frontier` is where the bump stopped in the
+    /// current one. Empty if there's no memory for the bitmap.
+    pub(crate) unsafe fn build(
+        chunks: *mut ChunkHeader,
+        frontier: *mut u8,
+        line_size: usize,
+    ) -> Self {
+        unsafe {
+            let mut map = Self::new();
+            map.line_size = line_size;
+
+            let (mut row_count, mut word_count) = (0, 0);
+            let mut chunk = chunks;
+            while !chunk.is_null() {
+                row_count += 1;
+                word_count += map.row_words((*chunk).size);
+                chunk = (*chunk).next;
+            }
+            if row_count == 0 {
+                return map;
+            }
+
+            let bytes = row_count * size_of::<Row>() + word_count * size_of::<u64>();
+            let mapped = (bytes + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            let memory = map_chunk(mapped);
+            if memory.is_null() {
+                return map;
+            }
+            map.rows = memory as *mut Row;
+            map.words = memory.add(row_count * size_of::<Row>()) as *mut u64;
+            map.mapped = mapped;
+
+            let mut word = 0;
+            let mut chunk = chunks;
+            while !chunk.is_null() {
+                let row = Row { base: chunk as usize, size: (*chunk).size, word };
+                map.rows.add(map.row_count).write(row);
+                map.row_count += 1;
+                map.mark(row, row.base, row.base + CHUNK_HEADER);
+                map.mark_blocks(row, frontier);
+                word += map.row_words(row.size);
+                chunk = (*chunk).next;
+            }
+            map
+        }
+    }
+
+    // Walks boundary tags up to the epilogue, or the frontier if it's in here
+    unsafe fn mark_blocks(&mut self, row: Row, frontier: *mut u8) {
+        unsafe {
+            let mut block = (row.base + CHUNK_HEADER) as *mut BlockHeader;
+            while block as *mut u8 != frontier {
+                let size = BlockHeader::size(block);
+                if size == 0 {
+                    break; // Epilogue
+                }
+                if BlockHeader::is_used(block) {
+                    self.mark(row, block as usize, block as usize + size);
+                }
+                block = BlockHeader::next(block);
+            }
+        }
+    }
+
+    // Sets the bit of every line `start..end` touches
+    unsafe fn mark(&mut self, row: Row, start: usize, end: usize) {
+        unsafe {
+            let first = (start - row.base) / self.line_size;
+            let last = (end - 1 - row.base) / self.line_size;
+            for line in first..=last {
+                *self.words.add(row.word + line / 64) |= 1 << (line % 64);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn row_words(&self, size: usize) -> usize {
+        (size / self.line_size).div_ceil(64)
+    }
+
+    #[inline]
+    fn rows(&self) -> &[Row] {
+        if self.rows.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.rows, self.row_count) }
+    }
+
+    // The bits of one row, the last word may run past its lines
+    #[inline]
+    fn bits(&self, row: &Row) -> &[u64] {
+        unsafe { core::slice::from_raw_parts(self.words.add(row.word), self.row_words(row.size)) }
+    }
+
+    #[inline]
+    pub fn chunk_count(&self) -> usize {
+        self.row_count
+    }
+
+    #[inline]
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    /// Lines across every chunk, used or not.
+    pub fn lines(&self) -> usize {
+        self.rows().iter().map(|row| row.size / self.line_size).sum()
+    }
+
+    pub fn used_lines(&self) -> usize {
+        self.rows()
+            .iter()
+            .map(|row| self.bits(row).iter().map(|word| word.count_ones() as usize).sum::<usize>())
+            .sum()
+    }
+
+    /// `line` of the `chunk`'th chunk, newest chunk first.
+    pub fn is_used(&self, chunk: usize, line: usize) -> bool {
+        let row = &self.rows()[chunk];
+        if line >= row.size / self.line_size {
+            panic!("MemoryMap exploded: line {} past the end of chunk {}", line, chunk);
+        }
+        self.bits(row)[line / 64] & (1 << (line % 64)) != 0
+    }
+
+    /// Longest stretch of free lines in any one chunk, what the biggest
+    /// request that still fits without a new chunk looks like.
+    pub fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        for row in self.rows() {
+            let mut run = 0;
+            for line in 0..row.size / self.line_size {
+                if self.bits(row)[line / 64] & (1 << (line % 64)) == 0 {
+                    run += 1;
+                    largest = largest.max(run);
+                } else {
+                    run = 0;
+                }
+            }
+        }
+        largest * self.line_size
+    }
+
+    /// One row of `width` characters per chunk, darker is fuller:
+    /// `0x7f1234000000 1024KB |@@@@%#*=-:.    | 63%`
+    pub fn write_ascii<W: Write>(&self, out: &mut W, width: usize) -> fmt::Result {
+        let width = width.max(1);
+        for row in self.rows() {
+            let lines = row.size / self.line_size;
+            let per_char = lines.div_ceil(width).max(1);
+            let bits = self.bits(row);
+            let used: usize = bits.iter().map(|word| word.count_ones() as usize).sum();
+
+            write!(out, "{:#x} {}KB |", row.base, row.size >> 10)?;
+            let mut line = 0;
+            while line < lines {
+                let end = (line + per_char).min(lines);
+                let set = (line..end).filter(|&l| bits[l / 64] & (1 << (l % 64)) != 0).count();
+                let shade = (set * (RAMP.len() - 1)).div_ceil(end - line);
+                out.write_char(RAMP[shade] as char)?;
+                line = end;
+            }
+            writeln!(out, "| {}%", used * 100 / lines.max(1))?;
+        }
+        Ok(())
+    }
+
+    /// The whole map as one JSON object. Each chunk's `bits` is hex, every
+    /// 16 digits one u64 of 64 lines, its low bit the first of them.
+    pub fn write_json<W: Write>(&self, out: &mut W) -> fmt::Result {
+        write!(
+            out,
+            "{{\"line_size\":{},\"lines\":{},\"used_lines\":{},\"chunks\":[",
+            self.line_size,
+            self.lines(),
+            self.used_lines()
+        )?;
+        for (i, row) in self.rows().iter().enumerate() {
+            let bits = self.bits(row);
+            let used: usize = bits.iter().map(|word| word.count_ones() as usize).sum();
+            if i > 0 {
+                out.write_char(',')?;
+            }
+            write!(out, "{{\"base\":{},\"size\":{},\"used_lines\":{},\"bits\":\"", row.base, row.size, used)?;
+            for word in bits {
+                write!(out, "{:016x}", word)?;
+            }
+            out.write_str("\"}")?;
+        }
+        out.write_str("]}")
+    }
+}
+
+impl Default for MemoryMap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MemoryMap {
+    fn drop(&mut self) {
+        if self.mapped != 0 {
+            unsafe { unmap_chunk(self.rows as *mut u8, self.mapped) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::alloc::Allocator;
+    use super::super::block::{block_size, HEADER};
+    use core::alloc::Layout;
+    use std::string::String;
+
+    #[test]
+    fn test_bit_placement() {
+        let mut heap = Allocator::new();
+        let small = Layout::from_size_align(5000, 8).unwrap();
+        let gap = Layout::from_size_align(20_000, 8).unwrap();
+        let a = heap.allocate(small) as usize;
+        let b = heap.allocate(gap);
+        let c = heap.allocate(small) as usize;
+        unsafe { heap.dealloc(b, gap) };
+
+        let map = heap.memory_map();
+        assert_eq!(map.chunk_count(), 1);
+        assert_eq!(map.lines(), (1 << 20) / map.line_size());
+
+        // The first block sits right behind the chunk header
+        let base = a - HEADER - CHUNK_HEADER;
+        let line = |addr: usize| (addr - base) / map.line_size();
+        let a_last = line(a - HEADER + block_size(5000) - 1);
+        let (c_first, c_last) = (line(c - HEADER), line(c - HEADER + block_size(5000) - 1));
+
+        // Header and a, then c, the freed gap and everything past the frontier blank
+        for l in 0..map.lines() {
+            let used = l <= a_last || (c_first..=c_last).contains(&l);
+            assert_eq!(map.is_used(0, l), used, "line {}", l);
+        }
+        assert_eq!(map.used_lines(), a_last + 1 + c_last - c_first + 1);
+        assert_eq!(map.largest_free_run(), (map.lines() - c_last - 1) * map.line_size());
+
+        // First 64 lines are all header and a, low bit first
+        let mut json = String::new();
+        map.write_json(&mut json).unwrap();
+        assert!(json.contains("\"bits\":\"ffffffffffffffff"));
+        assert!(json.contains(&std::format!("\"used_lines\":{}", map.used_lines())));
+    }
+}
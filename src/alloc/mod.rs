@@ -1 +1,46 @@
+
+#[allow(clippy::module_inception)]
 mod alloc;
+mod arena;
+mod backend;
+mod block;
+mod buddy;
+mod compact;
+mod concurrent;
+mod direct;
+mod fault;
+#[cfg(target_os = "linux")]
+mod guard;
+mod memmap;
+#[cfg(feature = "poison")]
+mod poison;
+mod pool;
+mod shared;
+mod slab;
+mod source;
+mod stats;
+mod tlsf;
+#[cfg(feature = "track")]
+mod track;
+
+pub use alloc::{Allocator, OomHandler, PressureHandler};
+pub use arena::{Arena, Mark};
+pub use backend::Backend;
+pub use buddy::Buddy;
+pub use compact::{CompactHeap, Handle};
+pub use concurrent::{ConcurrentBump, LocalCache, LOCAL_SLICE};
+pub use fault::FaultPolicy;
+#[cfg(target_os = "linux")]
+pub use guard::GuardMode;
+pub use memmap::MemoryMap;
+#[cfg(feature = "poison")]
+pub use poison::POISON;
+pub use pool::{Pool, PoolHandle};
+pub use shared::SharedAllocator;
+#[cfg(windows)]
+pub use source::{HeapChunks, VirtualAllocChunks};
+pub use source::{ChunkSource, OsChunks, StaticRegion};
+pub use stats::{AllocStats, HISTOGRAM_BUCKETS};
+pub use tlsf::Tlsf;
+#[cfg(feature = "track")]
+pub use track::Site;
@@ -0,0 +1,139 @@
+//! Freed memory gets painted, reused memory gets checked for smudges.
+//! Anything that isn't the pattern anymore was written after free, and we
+//! explode with where and how big the allocation was. Only slabs and chunk
+//! blocks, direct mappings are gone on free and fault on their own. Free
+//! blocks that merged only remember the last size freed into them.
+//!
+//! Freed layout, the free list owns the front:
+//! `[links][size][poison ...]`, `size` only if there's room for it
+
+use core::mem::size_of;
+
+use super::block::FreeLinks;
+
+/// Easy to spot in a hex dump, and not a pointer or a small number.
+pub const POISON: u8 = 0xde;
+
+const WORD: usize = size_of::<usize>();
+
+// How much of the front each free list claims
+pub(crate) const SLAB_LINK: usize = size_of::<*mut u8>();
+pub(crate) const BLOCK_LINK: usize = size_of::<FreeLinks>();
+
+// Poison over everything from `start` on, `len` bytes of it
+#[inline]
+pub(crate) unsafe fn fill(start: *mut u8, len: usize) {
+    unsafe {
+        core::ptr::write_bytes(start, POISON, len);
+    }
+}
+
+/// Paints a freed object behind its `link` bytes of free list, remembering
+/// `size` right after them if it fits.
+#[inline]
+pub(crate) unsafe fn mark(ptr: *mut u8, link: usize, len: usize, size: usize) {
+    unsafe {
+        if len >= link + WORD {
+            (ptr.add(link) as *mut usize).write(size);
+            fill(ptr.add(link + WORD), len - link - WORD);
+        }
+    }
+}
+
+/// Rewrites just the size, for objects cut out of one that's already painted.
+#[inline]
+pub(crate) unsafe fn tag(ptr: *mut u8, link: usize, len: usize, size: usize) {
+    unsafe {
+        if len >= link + WORD {
+            (ptr.add(link) as *mut usize).write(size);
+        }
+    }
+}
+
+/// Size `mark` or `tag` left behind, 0 if there was no room for one.
+#[inline]
+pub(crate) unsafe fn size(ptr: *mut u8, link: usize, len: usize) -> usize {
+    unsafe {
+        if len >= link + WORD { (ptr.add(link) as *const usize).read() } else { 0 }
+    }
+}
+
+/// Where the paint starts on an object marked with `link`, `len` if nowhere.
+#[inline(always)]
+pub(crate) const fn start(link: usize, len: usize) -> usize {
+    if len >= link + WORD { link + WORD } else { len }
+}
+
+/// Checks `from..to` of a freed object at `ptr` is still all poison,
+/// explodes with the first byte that isn't.
+#[inline]
+pub(crate) unsafe fn check(ptr: *mut u8, link: usize, from: usize, to: usize) {
+    unsafe {
+        let bytes = core::slice::from_raw_parts(ptr.add(from), to.saturating_sub(from));
+        if let Some(at) = bytes.iter().position(|&b| b != POISON) {
+            let size = (ptr.add(link) as *const usize).read();
+            panic!(
+                "Poison exploded: {:p} written after free at offset {}, allocation was {} bytes",
+                ptr,
+                from + at,
+                size,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::alloc::Allocator;
+    use core::alloc::Layout;
+    use std::format;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::string::String;
+
+    #[test]
+    #[should_panic(expected = "written after free at offset 40, allocation was 100 bytes")]
+    fn test_slab_write_after_free() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = heap.allocate(layout);
+        unsafe {
+            heap.dealloc(ptr, layout);
+            ptr.add(40).write(7);
+        }
+        heap.allocate(layout); // Same class, same slot back
+    }
+
+    #[test]
+    #[should_panic(expected = "written after free at offset 100, allocation was 3000 bytes")]
+    fn test_block_write_after_free() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(3000, 8).unwrap();
+        let ptr = heap.allocate(layout);
+        let _fence = heap.allocate(layout); // Keeps it off the frontier
+        unsafe {
+            heap.dealloc(ptr, layout);
+            ptr.add(100).write(7);
+        }
+        heap.allocate(layout); // First fit is the block we just freed
+    }
+
+    #[test]
+    fn test_reports_address() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = heap.allocate(layout);
+        unsafe {
+            heap.dealloc(ptr, layout);
+            ptr.add(63).write(!POISON);
+        }
+        let err = catch_unwind(AssertUnwindSafe(|| heap.allocate(layout))).unwrap_err();
+        let message = err.downcast::<String>().unwrap();
+        assert_eq!(
+            *message,
+            format!("Poison exploded: {:p} written after free at offset 63, allocation was 64 bytes", ptr)
+        );
+    }
+}
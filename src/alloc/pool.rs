@@ -0,0 +1,294 @@
+//! Same-size objects, O(1) in and out.
+//! Slots come off a bump pointer the first time and off an intrusive free
+//! list after that, the link lives where the value used to be. Backing is
+//! either chunks mapped like `Allocator`'s, or a buffer you hand in, which
+//! never grows. `reset` throws every slot back at once, destructors don't run.
+//!
+//! Handles remember which pool and which round of it they came from, so one
+//! from another pool, or from before a `reset`, gets `None` and not a slot
+//! somebody else owns now.
+
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of, ManuallyDrop, MaybeUninit};
+use core::ptr::{drop_in_place, null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+use super::block::{ChunkHeader, CHUNK_HEADER};
+
+// A value while it's live, a link while it's free
+#[repr(C)]
+union Slot<T> {
+    value: ManuallyDrop<T>,
+    next: *mut Slot<T>,
+}
+
+// Pool ids, handed out on the first `alloc_handle` so `new` can stay const
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Owned slot from `Pool::alloc_handle`. Not `Copy`, so it can only go back
+/// to the pool once.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PoolHandle<T> {
+    slot: NonNull<T>,
+    pool: usize,       // Id of the pool it came from, never 0
+    generation: usize, // That pool's reset count back then
+}
+
+pub struct Pool<T> {
+    free: *mut Slot<T>,        // Freed slots, newest first
+    cursor: *mut u8,           // Next never-used slot
+    limit: *mut u8,            // End of the region cursor walks
+    first: *mut ChunkHeader,   // Chunks in bump order, spares hang off the end
+    current: *mut ChunkHeader, // Null until the first bump after a reset
+    buffer: (*mut u8, usize),  // Caller's memory, null if chunk backed
+    chunk_size: usize,
+    live: usize,
+    id: usize,         // 0 until the first handle goes out
+    generation: usize, // Bumped by every reset, older handles stop working
+    _marker: PhantomData<T>,
+}
+
+impl<T> Pool<T> {
+    const SLOT: usize = size_of::<Slot<T>>();
+    const ALIGN: usize = align_of::<Slot<T>>();
+
+    #[inline]
+    pub const fn new() -> Self {
+        Self::with_chunk_size(64 << 10) // 64KB chunks, hot objects are small
+    }
+
+    #[inline]
+    pub const fn with_chunk_size(chunk_size: usize) -> Self {
+        Self {
+            free: null_mut(),
+            cursor: null_mut(),
+            limit: null_mut(),
+            first: null_mut(),
+            current: null_mut(),
+            buffer: (null_mut(), 0),
+            chunk_size: (chunk_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            live: 0,
+            id: 0,
+            generation: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Pool living entirely in `buffer`, nothing gets mapped. Once the buffer
+    /// is full, `alloc` returns null.
+    #[inline]
+    pub fn from_buffer(buffer: &'static mut [MaybeUninit<u8>]) -> Self {
+        let mut pool = Self::with_chunk_size(0);
+        pool.buffer = (buffer.as_mut_ptr() as *mut u8, buffer.len());
+        pool.rewind_buffer();
+        pool
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Moves `value` into a slot, null if there's no memory left for one.
+    #[inline]
+    pub fn alloc(&mut self, value: T) -> *mut T {
+        let slot = self.take_slot();
+        if slot.is_null() {
+            return null_mut(); // Out of slots
+        }
+        unsafe { (slot as *mut T).write(value) };
+        self.live += 1;
+        slot as *mut T
+    }
+
+    /// Drops the value and puts its slot back on the list.
+    ///
+    /// # Safety
+    /// `ptr` must have come from this pool's `alloc`, and only once.
+    #[inline]
+    pub unsafe fn free(&mut self, ptr: *mut T) {
+        unsafe {
+            if ptr.is_null() {
+                return;
+            }
+            drop_in_place(ptr);
+            self.push_slot(ptr as *mut Slot<T>);
+        }
+    }
+
+    #[inline]
+    pub fn alloc_handle(&mut self, value: T) -> Option<PoolHandle<T>> {
+        if self.id == 0 {
+            self.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        }
+        let (pool, generation) = (self.id, self.generation);
+        NonNull::new(self.alloc(value)).map(|slot| PoolHandle { slot, pool, generation })
+    }
+
+    /// None if `handle` is from another pool or from before a `reset`.
+    #[inline]
+    pub fn get(&self, handle: &PoolHandle<T>) -> Option<&T> {
+        if !self.owns(handle) {
+            return None;
+        }
+        Some(unsafe { handle.slot.as_ref() })
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, handle: &mut PoolHandle<T>) -> Option<&mut T> {
+        if !self.owns(handle) {
+            return None;
+        }
+        Some(unsafe { handle.slot.as_mut() })
+    }
+
+    /// Hands the value back out and frees the slot. None, and the pool
+    /// untouched, if `handle` isn't one of this round's.
+    #[inline]
+    pub fn free_handle(&mut self, handle: PoolHandle<T>) -> Option<T> {
+        if !self.owns(&handle) {
+            return None;
+        }
+        let slot = handle.slot.as_ptr();
+        let value = unsafe { slot.read() };
+        self.push_slot(slot as *mut Slot<T>);
+        Some(value)
+    }
+
+    /// Every slot is free again, chunks stay mapped for the next round.
+    /// Live values are forgotten, not dropped, and their handles go stale.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.free = null_mut();
+        self.live = 0;
+        self.generation += 1;
+        if self.buffer.0.is_null() {
+            self.current = null_mut();
+            self.cursor = null_mut();
+            self.limit = null_mut();
+        } else {
+            self.rewind_buffer();
+        }
+    }
+
+    #[inline(always)]
+    fn owns(&self, handle: &PoolHandle<T>) -> bool {
+        handle.pool == self.id && handle.generation == self.generation
+    }
+
+    #[inline(always)]
+    fn push_slot(&mut self, slot: *mut Slot<T>) {
+        unsafe { (*slot).next = self.free };
+        self.free = slot;
+        self.live -= 1;
+    }
+
+    #[inline(always)]
+    fn take_slot(&mut self) -> *mut Slot<T> {
+        let slot = self.free;
+        if !slot.is_null() {
+            self.free = unsafe { (*slot).next };
+            return slot;
+        }
+        if self.cursor as usize + Self::SLOT <= self.limit as usize {
+            let slot = self.cursor as *mut Slot<T>;
+            self.cursor = unsafe { self.cursor.add(Self::SLOT) };
+            return slot;
+        }
+        if self.buffer.0.is_null() { self.next_chunk() } else { null_mut() }
+    }
+
+    #[inline]
+    fn rewind_buffer(&mut self) {
+        let (start, len) = self.buffer;
+        let aligned = (start as usize + Self::ALIGN - 1) & !(Self::ALIGN - 1);
+        self.cursor = aligned as *mut u8;
+        self.limit = (start as usize + len).max(aligned) as *mut u8;
+    }
+
+    // Moves on to the next spare chunk, or maps one in right after current
+    #[cold]
+    fn next_chunk(&mut self) -> *mut Slot<T> {
+        let offset = (CHUNK_HEADER + Self::ALIGN - 1) & !(Self::ALIGN - 1);
+        unsafe {
+            let next = if self.current.is_null() { self.first } else { (*self.current).next };
+            let chunk = if !next.is_null() {
+                next
+            } else {
+                let needed = offset + Self::SLOT;
+                let chunk_size = self.chunk_size.max((needed + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+                let chunk = map_chunk(chunk_size) as *mut ChunkHeader;
+                if chunk.is_null() {
+                    return null_mut();
+                }
+                (*chunk).size = chunk_size;
+                (*chunk).next = null_mut();
+                if self.current.is_null() {
+                    self.first = chunk;
+                } else {
+                    (*self.current).next = chunk;
+                }
+                chunk
+            };
+            self.current = chunk;
+            self.cursor = (chunk as *mut u8).add(offset);
+            self.limit = (chunk as *mut u8).add((*chunk).size);
+        }
+        self.take_slot()
+    }
+}
+
+impl<T> Default for Pool<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        let mut chunk = self.first;
+        while !chunk.is_null() {
+            unsafe {
+                let next = (*chunk).next;
+                unmap_chunk(chunk as *mut u8, (*chunk).size);
+                chunk = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_handles() {
+        let mut pool: Pool<u64> = Pool::new();
+        let mut other: Pool<u64> = Pool::new();
+        let mut handle = pool.alloc_handle(7).unwrap();
+        let mut foreign = other.alloc_handle(9).unwrap();
+
+        *pool.get_mut(&mut handle).unwrap() += 1;
+        assert_eq!(pool.get(&handle), Some(&8));
+        assert_eq!(pool.get(&foreign), None);
+        assert_eq!(pool.get_mut(&mut foreign), None);
+        assert_eq!(pool.free_handle(foreign), None);
+        assert_eq!(other.len(), 1);
+
+        // The slot goes out again after the reset, the old handle can't reach it
+        pool.reset();
+        let fresh = pool.alloc_handle(1).unwrap();
+        assert_eq!(pool.get(&handle), None);
+        assert_eq!(pool.get_mut(&mut handle), None);
+        assert_eq!(pool.free_handle(handle), None);
+        assert_eq!(pool.free_handle(fresh), Some(1));
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,359 @@
+//! `Allocator` behind a spin lock, so one instance can be shared.
+//! Drop it behind `#[global_allocator]` and libc malloc never gets a word in:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static GLOBAL: SharedAllocator = SharedAllocator::new();
+//! ```
+//!
+//! Chunks come from mmap or VirtualAlloc, never the global allocator, so it
+//! doesn't end up calling itself. Targets with neither have nothing to map,
+//! build one over a `StaticRegion` there.
+//!
+//! `&SharedAllocator` is also a `core::alloc::Allocator`, so each subsystem
+//! can get its own arena without touching container code:
+//!
+//! ```
+//! use substd::alloc::SharedAllocator;
+//! use substd::vec::{Vec, SIMD_ALIGN};
+//!
+//! static NET: SharedAllocator = SharedAllocator::new();
+//! let mut packets = Vec::<u8, _>::new_in(8, 0, SIMD_ALIGN, &NET);
+//! packets.push(0x45);
+//! assert_eq!(packets.get(0), 0x45);
+//! ```
+//!
+//! Any `Backend` goes underneath, `Allocator` is just the default:
+//!
+//! ```ignore
+//! static LONG_LIVED: SharedAllocator<Buddy> = SharedAllocator::from_backend(Buddy::new());
+//! ```
+
+use core::alloc::{AllocError, Allocator as CoreAllocator, GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::alloc::{Allocator, OomHandler, PressureHandler};
+use super::backend::Backend;
+use super::fault::FaultPolicy;
+#[cfg(target_os = "linux")]
+use super::guard::GuardMode;
+use super::memmap::MemoryMap;
+use super::source::ChunkSource;
+use super::stats::AllocStats;
+
+/// `B` is whatever does the work, `Allocator` unless you pick another `Backend`.
+pub struct SharedAllocator<B = Allocator> {
+    locked: AtomicBool,
+    inner: UnsafeCell<B>,
+}
+
+// The lock is the only way in, and whichever thread holds it gets `&mut B`,
+// so `B` has to be fine on another thread
+unsafe impl<B: Send> Sync for SharedAllocator<B> {}
+
+impl<B> SharedAllocator<B> {
+    /// Any other backend, `SharedAllocator::from_backend(Buddy::new())`.
+    #[inline]
+    pub const fn from_backend(backend: B) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(backend),
+        }
+    }
+
+    /// Runs `f` with the lock held. Don't allocate through `self` inside it,
+    /// that's a deadlock, not a recursion.
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    pub fn with<R>(&self, f: impl FnOnce(&mut B) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        let result = f(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl SharedAllocator {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(Allocator::new()),
+        }
+    }
+
+    /// Guard-page debug build of the global allocator, see `Allocator::guarded`.
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub const fn guarded(mode: GuardMode) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(Allocator::guarded(mode)),
+        }
+    }
+}
+
+impl Default for SharedAllocator {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: ChunkSource> SharedAllocator<Allocator<S>> {
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.with(|a| a.used_bytes())
+    }
+
+    #[inline]
+    pub fn free_bytes(&self) -> usize {
+        self.with(|a| a.free_bytes())
+    }
+
+    #[inline]
+    pub fn max_bytes(&self) -> usize {
+        self.with(|a| a.max_bytes())
+    }
+
+    #[inline]
+    pub fn stats(&self) -> AllocStats {
+        self.with(|a| a.stats())
+    }
+
+    #[inline]
+    pub fn memory_map(&self) -> MemoryMap {
+        self.with(|a| a.memory_map())
+    }
+
+    #[inline]
+    pub fn set_limits(&self, soft: usize, hard: usize) {
+        self.with(|a| a.set_limits(soft, hard))
+    }
+
+    /// The handler runs with the lock held, free through the `&mut Allocator`
+    /// it's given, not through `self`.
+    #[inline]
+    pub fn set_pressure_handler(&self, handler: Option<PressureHandler<S>>) {
+        self.with(|a| a.set_pressure_handler(handler))
+    }
+
+    /// Also runs with the lock held, so no allocating in there either.
+    #[inline]
+    pub fn set_oom_handler(&self, handler: Option<OomHandler>) {
+        self.with(|a| a.set_oom_handler(handler))
+    }
+
+    #[inline]
+    pub fn set_fault_policy(&self, policy: FaultPolicy) {
+        self.with(|a| a.set_fault_policy(policy))
+    }
+
+    #[inline]
+    pub fn faults_injected(&self) -> u64 {
+        self.with(|a| a.faults_injected())
+    }
+}
+
+// With `track` on, every hop down to the backend passes the caller along,
+// closures included, or every allocation would be blamed on this file
+unsafe impl<B: Backend> GlobalAlloc for SharedAllocator<B> {
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with(
+            #[cfg_attr(feature = "track", track_caller)]
+            |a| a.allocate(layout),
+        )
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with(|a| unsafe { a.dealloc(ptr, layout) })
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with(
+            #[cfg_attr(feature = "track", track_caller)]
+            |a| unsafe { a.realloc(ptr, layout, new_size) },
+        )
+    }
+}
+
+// The handle is just a reference, copy it around as much as you like
+unsafe impl<B: Backend> CoreAllocator for &SharedAllocator<B> {
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.with(
+            #[cfg_attr(feature = "track", track_caller)]
+            |a| a.allocate(layout),
+        );
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, layout.size())),
+            None => Err(AllocError),
+        }
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.with(|a| unsafe { a.dealloc(ptr.as_ptr(), layout) })
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.with(
+            #[cfg_attr(feature = "track", track_caller)]
+            |a| unsafe { a.grow(ptr.as_ptr(), old, new) },
+        );
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, new.size())),
+            None => Err(AllocError),
+        }
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "track", track_caller)]
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.with(
+            #[cfg_attr(feature = "track", track_caller)]
+            |a| unsafe { a.shrink(ptr.as_ptr(), old, new) },
+        );
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, new.size())),
+            None => Err(AllocError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::alloc::{Buddy, Tlsf};
+    use std::thread;
+    use std::vec::Vec;
+
+    static SHARED: SharedAllocator<Buddy> = SharedAllocator::from_backend(Buddy::new());
+
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn test_threads_share_one_backend() {
+        assert_sync::<SharedAllocator>();
+        assert_sync::<SharedAllocator<Buddy>>();
+        assert_sync::<SharedAllocator<Tlsf>>();
+
+        let threads: Vec<_> = (0..4u8)
+            .map(|id| {
+                thread::spawn(move || {
+                    let layout = Layout::from_size_align(64, 8).unwrap();
+                    for _ in 0..1000 {
+                        let ptr = unsafe { SHARED.alloc(layout) };
+                        assert!(!ptr.is_null());
+                        unsafe {
+                            ptr.write_bytes(id, 64);
+                            assert!((0..64).all(|i| *ptr.add(i) == id));
+                            SHARED.dealloc(ptr, layout);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(SHARED.with(|buddy| buddy.used_bytes()), 0);
+    }
+
+    #[test]
+    fn test_vec_in_shared() {
+        let shared = SharedAllocator::new();
+        {
+            let mut packets = crate::vec::Vec::<u8, _>::new_in(8, 0, crate::vec::SIMD_ALIGN, &shared);
+            packets.push(0);
+            let first = shared.used_bytes();
+            assert!(first > 0);
+
+            // Every doubling is a fresh buffer from `shared`, the old one handed back
+            for i in 1..1000 {
+                packets.push(i as u8);
+            }
+            assert!(packets.capacity() >= 1000);
+            assert!(shared.used_bytes() >= 1000 && shared.used_bytes() > first);
+            assert!((0..1000).all(|i| packets.get(i) == i as u8 as u64));
+        }
+        assert_eq!(shared.used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_vec_try_reserve_recovers() {
+        let shared = SharedAllocator::new();
+        let mut packets = crate::vec::Vec::<u8, _>::new_in(8, 0, crate::vec::SIMD_ALIGN, &shared);
+        packets.push(1);
+        packets.push(2);
+        let (capacity, used) = (packets.capacity(), shared.used_bytes());
+
+        shared.set_fault_policy(FaultPolicy::Nth(1));
+        assert_eq!(packets.try_reserve(1000), Err(AllocError));
+        assert_eq!(shared.faults_injected(), 1);
+
+        // Old buffer untouched, and nothing leaked on the way out
+        assert_eq!(packets.capacity(), capacity);
+        assert_eq!(shared.used_bytes(), used);
+        assert_eq!((packets.len(), packets.get(0), packets.get(1)), (2, 1, 2));
+
+        // Only the first one fails, the retry goes through
+        assert_eq!(packets.try_reserve(1000), Ok(()));
+        assert!(packets.capacity() >= 1002);
+        assert_eq!((packets.len(), packets.get(0), packets.get(1)), (2, 1, 2));
+        packets.push(3);
+        assert_eq!(packets.get(2), 3);
+    }
+
+    #[cfg(feature = "track")]
+    #[test]
+    fn test_tracks_the_real_caller() {
+        let shared = SharedAllocator::new();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let (ptr, line) = (unsafe { shared.alloc(layout) }, line!());
+        let handle = (&shared).allocate(layout).unwrap();
+        let handle_line = line!() - 1;
+
+        let lines: Vec<_> = shared.with(|a| {
+            a.live_sites().iter().map(|site| (site.ptr, site.location.line())).collect()
+        });
+        assert!(lines.contains(&(ptr as usize, line)));
+        assert!(lines.contains(&(handle.as_ptr() as *mut u8 as usize, handle_line)));
+
+        unsafe {
+            shared.dealloc(ptr, layout);
+            (&shared).deallocate(handle.cast(), layout);
+        }
+    }
+}
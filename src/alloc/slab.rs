@@ -0,0 +1,132 @@
+//! Segregated size classes for the small stuff.
+//! Power-of-two classes from 8 to 4096 bytes, each with its own free list.
+//! Slabs are carved out of the allocator's current chunk, page aligned, so
+//! every object is naturally aligned to its class size. 1.25x steps would
+//! waste less, but then alignment stops falling out for free.
+
+use core::ptr::null_mut;
+
+use super::alloc::PAGE_SIZE;
+#[cfg(feature = "poison")]
+use super::poison::{self, SLAB_LINK};
+
+pub(crate) const MIN_CLASS_SHIFT: u32 = 3; // 8 bytes, room for the free list link
+pub(crate) const MAX_CLASS_SHIFT: u32 = 12; // 4096 bytes, one page
+pub(crate) const CLASSES: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+pub(crate) const SLAB_SIZE: usize = PAGE_SIZE * 16; // 64KB, 16 slabs per 1MB chunk
+
+/// Picks the class for a request, or `None` if it's a job for whole chunks.
+#[inline(always)]
+pub(crate) fn class_of(size: usize, align: usize) -> Option<usize> {
+    let need = size.max(align);
+    if need > 1 << MAX_CLASS_SHIFT {
+        return None;
+    }
+    let shift = need.max(1 << MIN_CLASS_SHIFT).next_power_of_two().trailing_zeros();
+    Some((shift - MIN_CLASS_SHIFT) as usize)
+}
+
+#[inline(always)]
+pub(crate) const fn class_size(class: usize) -> usize {
+    1 << (class as u32 + MIN_CLASS_SHIFT)
+}
+
+pub(crate) struct SlabClasses {
+    free: [*mut u8; CLASSES],   // Intrusive free lists, next pointer in the first word
+    cursor: [*mut u8; CLASSES], // Untouched space in the newest slab
+    limit: [*mut u8; CLASSES],  // End of the newest slab
+}
+
+impl SlabClasses {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self {
+            free: [null_mut(); CLASSES],
+            cursor: [null_mut(); CLASSES],
+            limit: [null_mut(); CLASSES],
+        }
+    }
+
+    /// Pops a free object, or bumps one out of the newest slab.
+    /// Null means the class needs a fresh slab via `refill`.
+    #[inline(always)]
+    pub(crate) fn pop(&mut self, class: usize) -> *mut u8 {
+        let head = self.free[class];
+        if !head.is_null() {
+            self.free[class] = unsafe { *(head as *mut *mut u8) };
+            #[cfg(feature = "poison")]
+            unsafe {
+                let len = class_size(class);
+                poison::check(head, SLAB_LINK, poison::start(SLAB_LINK, len), len);
+            }
+            return head;
+        }
+
+        let cursor = self.cursor[class];
+        if cursor.is_null() || cursor == self.limit[class] {
+            return null_mut();
+        }
+        self.cursor[class] = unsafe { cursor.add(class_size(class)) };
+        cursor
+    }
+
+    #[inline(always)]
+    pub(crate) fn push(&mut self, class: usize, ptr: *mut u8) {
+        unsafe { *(ptr as *mut *mut u8) = self.free[class] };
+        self.free[class] = ptr;
+    }
+
+    /// Hands a fresh slab of `size` bytes to a class, `SLAB_SIZE` unless the
+    /// chunks are too small for it. Whatever was left of the old one is
+    /// abandoned, which only happens once it's used up anyway.
+    #[inline]
+    pub(crate) fn refill(&mut self, class: usize, slab: *mut u8, size: usize) {
+        self.cursor[class] = slab;
+        self.limit[class] = unsafe { slab.add(size) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::Allocator;
+    use core::alloc::Layout;
+
+    #[test]
+    fn test_class_of() {
+        assert_eq!(class_of(0, 1), Some(0));
+        assert_eq!(class_of(8, 8), Some(0));
+        assert_eq!(class_of(9, 8), Some(1));
+        assert_eq!(class_of(100, 8), Some(4));
+        assert_eq!(class_of(8, 64), Some(3)); // Alignment picks the class too
+        assert_eq!(class_of(4096, 8), Some(CLASSES - 1));
+        assert_eq!(class_of(4097, 8), None);
+        assert_eq!(class_of(8, 8192), None);
+        assert_eq!(class_size(4), 128);
+    }
+
+    #[test]
+    fn test_slab_reuse() {
+        let mut heap = Allocator::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let a = heap.allocate(layout);
+        let b = heap.allocate(layout);
+        assert_eq!(b as usize - a as usize, 128); // Next slot of the same slab
+        assert_eq!(a as usize % 128, 0);
+
+        // Last freed goes out first, and any size of the class takes it
+        unsafe {
+            heap.dealloc(a, layout);
+            heap.dealloc(b, layout);
+        }
+        let c = heap.allocate(Layout::from_size_align(128, 8).unwrap());
+        assert_eq!(c, b);
+        assert_eq!(heap.allocate(Layout::from_size_align(65, 1).unwrap()), a);
+
+        // Other classes don't see it
+        let d = heap.allocate(Layout::from_size_align(64, 8).unwrap());
+        assert_ne!(d, a);
+        assert_ne!(d, b);
+        assert_eq!(heap.used_bytes(), 128 * 2 + 64);
+    }
+}
@@ -0,0 +1,219 @@
+//! Where `Allocator` gets its chunks, picked when it's built.
+//! `OsChunks` is what you get by default, mmap on unix. `StaticRegion`
+//! carves chunks out of a buffer you hand in, no OS and no global
+//! allocator, so it's the one for bare metal. Windows gets `VirtualAlloc`
+//! and `HeapAlloc` flavours, straight from kernel32.
+
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+use super::alloc::{map_aligned, unmap_aligned};
+
+pub trait ChunkSource {
+    /// `size` bytes aligned to `align`, a power of two of at least a page.
+    /// Contents are whatever was there. Null if there's nothing left.
+    ///
+    /// # Safety
+    /// Whatever comes back goes back through this source's `unmap`, or nowhere.
+    unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8;
+
+    /// Takes back exactly what one `map` call handed out.
+    ///
+    /// # Safety
+    /// `ptr`, `size` and `align` are one earlier `map` of this source, not
+    /// given back yet, and nothing touches the memory afterwards.
+    unsafe fn unmap(&mut self, ptr: *mut u8, size: usize, align: usize);
+}
+
+/// Whatever `map_chunk` does on this target: mmap on unix, VirtualAlloc on
+/// Windows, nothing at all anywhere else.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OsChunks;
+
+impl ChunkSource for OsChunks {
+    #[inline]
+    unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            map_aligned(size, align)
+        }
+    }
+
+    #[inline]
+    unsafe fn unmap(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        unsafe {
+            unmap_aligned(ptr, size, align)
+        }
+    }
+}
+
+// A piece given back, the header lives in the piece itself
+struct Freed {
+    next: *mut Freed,
+    size: usize,
+}
+
+/// Chunks out of one `&'static mut [u8]`. Bumps through it, and reuses
+/// pieces that come back before bumping any further.
+pub struct StaticRegion {
+    base: *mut u8,
+    len: usize,
+    top: usize,        // Bump offset, everything below went out at some point
+    freed: *mut Freed, // Given back and not at the top, newest first
+}
+
+// It came in as `&'static mut`, so it's ours alone wherever it goes
+unsafe impl Send for StaticRegion {}
+
+impl StaticRegion {
+    #[inline]
+    pub fn new(region: &'static mut [u8]) -> Self {
+        Self { base: region.as_mut_ptr(), len: region.len(), top: 0, freed: null_mut() }
+    }
+
+    /// Bytes never handed out, pieces sitting on the freed list not included.
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.len - self.top
+    }
+
+    // First given-back piece that's big enough and aligned already
+    unsafe fn reuse(&mut self, size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            let mut link: *mut *mut Freed = &mut self.freed;
+            while !(*link).is_null() {
+                let piece = *link;
+                if (*piece).size == size && piece as usize & (align - 1) == 0 {
+                    *link = (*piece).next;
+                    return piece as *mut u8;
+                }
+                link = &mut (*piece).next;
+            }
+            null_mut()
+        }
+    }
+}
+
+impl ChunkSource for StaticRegion {
+    unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            let ptr = self.reuse(size, align);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            let start = self.base as usize + self.top;
+            let aligned = (start + align - 1) & !(align - 1);
+            let end = match aligned.checked_add(size) {
+                Some(end) if end <= self.base as usize + self.len => end,
+                _ => return null_mut(), // Region's used up
+            };
+            self.top = end - self.base as usize;
+            aligned as *mut u8
+        }
+    }
+
+    unsafe fn unmap(&mut self, ptr: *mut u8, size: usize, _align: usize) {
+        unsafe {
+            if ptr as usize + size == self.base as usize + self.top {
+                self.top = ptr as usize - self.base as usize; // Last out, first back
+                return;
+            }
+            if size >= size_of::<Freed>() {
+                let piece = ptr as *mut Freed;
+                (*piece).next = self.freed;
+                (*piece).size = size;
+                self.freed = piece;
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::{HeapChunks, VirtualAllocChunks};
+
+#[cfg(windows)]
+mod windows {
+    use core::ffi::c_void;
+    use core::mem::size_of;
+    use core::ptr::null_mut;
+
+    use super::ChunkSource;
+    use super::super::alloc::{GetProcessHeap, HeapAlloc, HeapFree, VirtualAlloc, VirtualFree};
+    use super::super::alloc::{GRANULARITY, MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_READWRITE, PAGE_SIZE};
+
+    /// Straight from `VirtualAlloc`, reserved and committed in one go.
+    /// Nothing past 64KB alignment, that's all the API promises.
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct VirtualAllocChunks;
+
+    impl ChunkSource for VirtualAllocChunks {
+        unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8 {
+            unsafe {
+                if align > GRANULARITY {
+                    return null_mut(); // Would need a reserve-and-retry dance
+                }
+                VirtualAlloc(null_mut(), size, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE) as *mut u8
+            }
+        }
+
+        #[inline]
+        unsafe fn unmap(&mut self, ptr: *mut u8, _size: usize, _align: usize) {
+            unsafe {
+                VirtualFree(ptr as *mut c_void, 0, MEM_RELEASE); // Release wants a size of 0
+            }
+        }
+    }
+
+    /// Out of a Win32 heap, the process heap unless you name one. Over-asks
+    /// by `align` and keeps the real pointer just in front of the chunk.
+    #[derive(Copy, Clone, Debug)]
+    pub struct HeapChunks {
+        heap: *mut c_void, // Null means the process heap, looked up on first use
+    }
+
+    impl HeapChunks {
+        #[inline]
+        pub const fn process() -> Self {
+            Self { heap: null_mut() }
+        }
+
+        /// `heap` from `HeapCreate`, it has to outlive the allocator.
+        #[inline]
+        pub const fn with_heap(heap: *mut c_void) -> Self {
+            Self { heap }
+        }
+
+        #[inline]
+        unsafe fn heap(&mut self) -> *mut c_void {
+            unsafe {
+                if self.heap.is_null() {
+                    self.heap = GetProcessHeap();
+                }
+                self.heap
+            }
+        }
+    }
+
+    impl ChunkSource for HeapChunks {
+        unsafe fn map(&mut self, size: usize, align: usize) -> *mut u8 {
+            unsafe {
+                let align = align.max(PAGE_SIZE);
+                let raw = HeapAlloc(self.heap(), 0, size + align) as *mut u8;
+                if raw.is_null() {
+                    return null_mut();
+                }
+                let ptr = ((raw as usize + size_of::<usize>() + align - 1) & !(align - 1)) as *mut u8;
+                (ptr as *mut *mut u8).sub(1).write(raw);
+                ptr
+            }
+        }
+
+        #[inline]
+        unsafe fn unmap(&mut self, ptr: *mut u8, _size: usize, _align: usize) {
+            unsafe {
+                let raw = (ptr as *mut *mut u8).sub(1).read();
+                HeapFree(self.heap(), 0, raw as *mut c_void);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Allocation counters, cheap enough to leave on in release.
+//! Everything is a plain add, the histogram bucket is one `leading_zeros`.
+
+// Bucket 0 is zero-size, bucket n holds sizes in [2^(n-1), 2^n)
+pub const HISTOGRAM_BUCKETS: usize = usize::BITS as usize + 1;
+
+/// Snapshot from `Allocator::stats()`.
+/// Byte counts are what's charged against `max_bytes`, after rounding.
+#[derive(Copy, Clone, Debug)]
+pub struct AllocStats {
+    pub current_bytes: usize, // Live right now, same as used_bytes
+    pub peak_bytes: usize,    // High water mark of current_bytes
+    pub total_allocated: u64, // Every byte ever handed out
+    pub total_freed: u64,     // Every byte ever handed back
+    pub alloc_count: u64,
+    pub free_count: u64,
+    pub chunk_count: usize,   // Chunks mapped right now
+    pub direct_count: usize,  // Oversized requests with their own mapping
+    pub padding_bytes: usize, // Live bytes lost to rounding and alignment
+    pub guard_bytes: usize,   // Freed guarded mappings, PROT_NONE for good
+    pub histogram: [u64; HISTOGRAM_BUCKETS], // Request sizes by power of two
+}
+
+impl AllocStats {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            current_bytes: 0,
+            peak_bytes: 0,
+            total_allocated: 0,
+            total_freed: 0,
+            alloc_count: 0,
+            free_count: 0,
+            chunk_count: 0,
+            direct_count: 0,
+            padding_bytes: 0,
+            guard_bytes: 0,
+            histogram: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    /// Which histogram bucket a request size lands in.
+    #[inline(always)]
+    pub const fn bucket(size: usize) -> usize {
+        (usize::BITS - size.leading_zeros()) as usize
+    }
+
+    // `charged` counts against the budget, `granted` is what the caller can use
+    #[inline(always)]
+    pub(crate) fn record_alloc(&mut self, requested: usize, charged: usize, granted: usize) {
+        self.current_bytes += charged;
+        if self.current_bytes > self.peak_bytes {
+            self.peak_bytes = self.current_bytes;
+        }
+        self.total_allocated += charged as u64;
+        self.alloc_count += 1;
+        self.padding_bytes += granted - requested;
+        self.histogram[Self::bucket(requested)] += 1;
+    }
+
+    #[inline(always)]
+    pub(crate) fn record_free(&mut self, requested: usize, charged: usize, granted: usize) {
+        self.current_bytes -= charged;
+        self.total_freed += charged as u64;
+        self.free_count += 1;
+        self.padding_bytes -= granted - requested;
+    }
+}
+
+impl Default for AllocStats {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::Allocator;
+    use super::super::block::{block_size, HEADER};
+    use core::alloc::Layout;
+
+    #[test]
+    fn test_known_sequence() {
+        let mut heap = Allocator::new();
+        let l = |size| Layout::from_size_align(size, 8).unwrap();
+
+        // A 128 slab slot, a chunk block and a 74 page direct mapping
+        let block_pad = block_size(5000) - HEADER - 5000;
+        let a = heap.allocate(l(100));
+        let b = heap.allocate(l(5000));
+        let c = heap.allocate(l(300_000));
+        let stats = heap.stats();
+        assert_eq!(stats.current_bytes, 128 + 5000 + 74 * 4096);
+        assert_eq!(stats.current_bytes, heap.used_bytes());
+        assert_eq!(stats.padding_bytes, 28 + block_pad + (74 * 4096 - 300_000));
+        assert_eq!((stats.direct_count, stats.chunk_count), (1, 1));
+
+        // Peak stays put through a free and a smaller allocation
+        unsafe { heap.dealloc(b, l(5000)) };
+        let d = heap.allocate(l(24));
+        let stats = heap.stats();
+        assert_eq!(stats.current_bytes, 128 + 74 * 4096 + 32);
+        assert_eq!(stats.peak_bytes, 128 + 5000 + 74 * 4096);
+        assert_eq!(stats.padding_bytes, 28 + (74 * 4096 - 300_000) + 8);
+
+        unsafe {
+            heap.dealloc(a, l(100));
+            heap.dealloc(c, l(300_000));
+            heap.dealloc(d, l(24));
+        }
+        let stats = heap.stats();
+        let total = (128 + 5000 + 74 * 4096 + 32) as u64;
+        assert_eq!((stats.total_allocated, stats.total_freed), (total, total));
+        assert_eq!((stats.alloc_count, stats.free_count), (4, 4));
+        assert_eq!((stats.current_bytes, stats.padding_bytes, stats.direct_count), (0, 0, 0));
+        assert_eq!(stats.peak_bytes, 128 + 5000 + 74 * 4096);
+
+        // One request each in 64..128, 4K..8K, 256K..512K and 16..32
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        for bucket in [7, 13, 19, 5] {
+            histogram[bucket] = 1;
+        }
+        assert_eq!(stats.histogram, histogram);
+        assert_eq!(AllocStats::bucket(0), 0);
+        assert_eq!(AllocStats::bucket(4096), 13);
+    }
+}
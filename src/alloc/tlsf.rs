@@ -0,0 +1,464 @@
+//! Two-Level Segregated Fit, for when "usually fast" isn't good enough.
+//! Free blocks are binned by size, first by power of two, then into 16
+//! linear steps inside it. Two levels of bitmaps say which bins have
+//! anything, so finding a block is two `trailing_zeros` and freeing one is
+//! a couple of pointer swaps. No loops, no OS, O(1) both ways.
+//!
+//! Memory is whatever you hand `add_region`, a static buffer on bare metal
+//! or anything else that lives forever. Region layout:
+//! `[block][block]...[sentinel]`, each block `[prev_phys][size][payload]`
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::backend::Backend;
+
+const ALIGN: usize = 16;
+const SL_SHIFT: u32 = 4; // 16 second-level bins per power of two
+const SL_COUNT: usize = 1 << SL_SHIFT;
+const FL_SHIFT: u32 = SL_SHIFT + ALIGN.trailing_zeros(); // Below 256 it's all linear
+const FL_MAX: u32 = 31; // Blocks stay under 2GB, so usize is enough on 32 bit
+const FL_COUNT: usize = (FL_MAX - FL_SHIFT + 1) as usize;
+const SMALL: usize = 1 << FL_SHIFT;
+
+const HEADER: usize = size_of::<Block>();
+const MIN_BLOCK: usize = HEADER + size_of::<Links>();
+const MAX_BLOCK: usize = (1 << FL_MAX) - ALIGN;
+const MAX_REQUEST: usize = 1 << (FL_MAX - 1); // Rounding up can't leave the top bin
+
+// Low bit of the size, sizes are multiples of 16
+const FREE: usize = 1;
+
+#[repr(C, align(16))]
+struct Block {
+    prev_phys: *mut Block, // Left neighbour, null for the first block of a region
+    size: usize,           // Whole block, header included, low bit = free
+}
+
+// Lives in the payload of free blocks only
+#[repr(C)]
+struct Links {
+    next: *mut Block,
+    prev: *mut Block,
+}
+
+pub struct Tlsf {
+    fl_bitmap: u32,                           // Bit per first level with anything in it
+    sl_bitmap: [u32; FL_COUNT],               // Bit per bin with anything in it
+    bins: [[*mut Block; SL_COUNT]; FL_COUNT], // Free list heads
+    capacity: usize,                          // Bytes across every region, headers included
+    used_bytes: usize,                        // Blocks handed out, headers included
+}
+
+// Bins point into regions handed over as `&'static mut`, nobody else has them
+unsafe impl Send for Tlsf {}
+
+impl Tlsf {
+    /// No memory yet, `add_region` before allocating anything.
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            bins: [[null_mut(); SL_COUNT]; FL_COUNT],
+            capacity: 0,
+            used_bytes: 0,
+        }
+    }
+
+    #[inline]
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Self {
+        let mut tlsf = Self::new();
+        tlsf.add_region(buffer);
+        tlsf
+    }
+
+    /// Hands `buffer` over for good. Anything past 2GB is ignored, false if
+    /// it's too small to hold a single block.
+    pub fn add_region(&mut self, buffer: &'static mut [u8]) -> bool {
+        let start = (buffer.as_mut_ptr() as usize + ALIGN - 1) & !(ALIGN - 1);
+        let end = (buffer.as_mut_ptr() as usize + buffer.len()) & !(ALIGN - 1);
+        if end < start + MIN_BLOCK + HEADER {
+            return false;
+        }
+        let size = (end - start - HEADER).min(MAX_BLOCK);
+
+        unsafe {
+            let block = start as *mut Block;
+            (*block).prev_phys = null_mut();
+            (*block).size = size | FREE;
+
+            // Never free, so nothing merges off the end
+            let sentinel = (start + size) as *mut Block;
+            (*sentinel).prev_phys = block;
+            (*sentinel).size = 0;
+
+            self.insert(block);
+        }
+        self.capacity += size + HEADER;
+        true
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    /// Null if nothing fits, there's nowhere to get more from.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if layout.size() > MAX_REQUEST || layout.align() > MAX_REQUEST {
+            return null_mut(); // Too big for any bin
+        }
+        let size = block_size(layout.size());
+        let align = layout.align();
+
+        unsafe {
+            // Over-ask so there's room to cut a whole block off the front
+            let search = if align <= ALIGN { size } else { size + align + MIN_BLOCK };
+            let mut block = self.take(search);
+            if block.is_null() {
+                return null_mut(); // Out of memory
+            }
+            if align > ALIGN {
+                block = self.align_block(block, align);
+            }
+            self.split(block, size);
+            set_free(block, false);
+            self.used_bytes += size_of_block(block);
+            payload(block)
+        }
+    }
+
+    /// Merges with free neighbours and puts the block back in its bin.
+    ///
+    /// # Safety
+    /// `ptr` must be null or have come from this heap, and not been freed
+    /// since.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, _layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            let block = from_payload(ptr);
+            self.used_bytes -= size_of_block(block);
+            self.release(block);
+        }
+    }
+
+    /// Grows into a free right neighbour or shrinks in place when it can,
+    /// moves otherwise. Null and `ptr` untouched if nothing fits.
+    ///
+    /// # Safety
+    /// Same as `dealloc`, and `layout` has to be the one `ptr` was allocated with.
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if ptr.is_null() {
+            return match Layout::from_size_align(new_size, layout.align()) {
+                Ok(new) => self.allocate(new),
+                Err(_) => null_mut(),
+            };
+        }
+        if new_size > MAX_REQUEST {
+            return null_mut(); // Too big for any bin
+        }
+
+        let size = block_size(new_size);
+        unsafe {
+            let block = from_payload(ptr);
+            let current = size_of_block(block);
+            let next = next_phys(block);
+            if size <= current || (is_free(next) && current + size_of_block(next) >= size) {
+                if size > current {
+                    self.remove(next);
+                    set_size(block, current + size_of_block(next));
+                    (*next_phys(block)).prev_phys = block;
+                }
+                self.split(block, size);
+                self.used_bytes = self.used_bytes - current + size_of_block(block);
+                return ptr;
+            }
+
+            let new_ptr = self.allocate(Layout::from_size_align_unchecked(new_size, layout.align()));
+            if new_ptr.is_null() {
+                return null_mut(); // Old block stays valid
+            }
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+            new_ptr
+        }
+    }
+
+    // Cuts the misaligned front off as its own free block
+    unsafe fn align_block(&mut self, block: *mut Block, align: usize) -> *mut Block {
+        unsafe {
+            let start = payload(block) as usize;
+            let mut aligned = (start + align - 1) & !(align - 1);
+            if aligned == start {
+                return block;
+            }
+            if aligned - start < MIN_BLOCK {
+                aligned = (start + MIN_BLOCK + align - 1) & !(align - 1);
+            }
+
+            let gap = aligned - start;
+            let rest = (block as *mut u8).add(gap) as *mut Block;
+            (*rest).prev_phys = block;
+            (*rest).size = size_of_block(block) - gap; // Taken, it's what we hand out
+            (*next_phys(rest)).prev_phys = rest;
+            set_size(block, gap);
+            self.insert(block); // Left neighbour is never free, no merging needed
+            rest
+        }
+    }
+
+    // Anything past `size` worth a block of its own goes back
+    #[inline]
+    unsafe fn split(&mut self, block: *mut Block, size: usize) {
+        unsafe {
+            let total = size_of_block(block);
+            if total - size < MIN_BLOCK {
+                return;
+            }
+            set_size(block, size);
+            let tail = next_phys(block);
+            (*tail).prev_phys = block;
+            (*tail).size = total - size; // Used until release says otherwise
+            (*next_phys(tail)).prev_phys = tail;
+            self.release(tail);
+        }
+    }
+
+    // Merges both ways, then bins whatever came out of it
+    unsafe fn release(&mut self, mut block: *mut Block) {
+        unsafe {
+            let prev = (*block).prev_phys;
+            if !prev.is_null() && is_free(prev) {
+                self.remove(prev);
+                set_size(prev, size_of_block(prev) + size_of_block(block));
+                block = prev;
+            }
+            let next = next_phys(block);
+            if is_free(next) {
+                self.remove(next);
+                set_size(block, size_of_block(block) + size_of_block(next));
+            }
+            (*next_phys(block)).prev_phys = block;
+            self.insert(block);
+        }
+    }
+
+    // First block in the first bin that's guaranteed to fit, off its list
+    #[inline]
+    unsafe fn take(&mut self, size: usize) -> *mut Block {
+        unsafe {
+            let (fl, sl) = mapping(round_up(size));
+            if fl >= FL_COUNT {
+                return null_mut();
+            }
+
+            let mut fl = fl;
+            let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+            if sl_map == 0 {
+                let fl_map = self.fl_bitmap & (!0u32).checked_shl(fl as u32 + 1).unwrap_or(0);
+                if fl_map == 0 {
+                    return null_mut(); // Nothing that big anywhere
+                }
+                fl = fl_map.trailing_zeros() as usize;
+                sl_map = self.sl_bitmap[fl];
+            }
+            let block = self.bins[fl][sl_map.trailing_zeros() as usize];
+            self.remove(block);
+            block
+        }
+    }
+
+    #[inline]
+    unsafe fn insert(&mut self, block: *mut Block) {
+        unsafe {
+            let (fl, sl) = mapping(size_of_block(block));
+            let head = self.bins[fl][sl];
+            links(block).write(Links { next: head, prev: null_mut() });
+            if !head.is_null() {
+                (*links(head)).prev = block;
+            }
+            self.bins[fl][sl] = block;
+            self.fl_bitmap |= 1 << fl;
+            self.sl_bitmap[fl] |= 1 << sl;
+            set_free(block, true);
+        }
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, block: *mut Block) {
+        unsafe {
+            let (fl, sl) = mapping(size_of_block(block));
+            let Links { next, prev } = links(block).read();
+            if !next.is_null() {
+                (*links(next)).prev = prev;
+            }
+            if prev.is_null() {
+                self.bins[fl][sl] = next;
+                if next.is_null() {
+                    self.sl_bitmap[fl] &= !(1 << sl);
+                    if self.sl_bitmap[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            } else {
+                (*links(prev)).next = next;
+            }
+            set_free(block, false);
+        }
+    }
+}
+
+impl Default for Tlsf {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Tlsf {
+    #[inline]
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        Tlsf::allocate(self, layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { Tlsf::dealloc(self, ptr, layout) }
+    }
+
+    #[inline]
+    unsafe fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { Tlsf::realloc(self, ptr, layout, new_size) }
+    }
+
+    #[inline]
+    fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+// Whole block for a payload, header included
+#[inline(always)]
+const fn block_size(payload: usize) -> usize {
+    let size = (payload + HEADER + ALIGN - 1) & !(ALIGN - 1);
+    if size < MIN_BLOCK { MIN_BLOCK } else { size }
+}
+
+// Bin a block of `size` lives in
+#[inline(always)]
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL {
+        return (0, size / (SMALL / SL_COUNT));
+    }
+    let msb = usize::BITS - 1 - size.leading_zeros();
+    let sl = (size >> (msb - SL_SHIFT)) ^ SL_COUNT;
+    ((msb - FL_SHIFT + 1) as usize, sl)
+}
+
+// Up to the next bin boundary, so anything in the bin we land in fits
+#[inline(always)]
+fn round_up(size: usize) -> usize {
+    if size < SMALL {
+        return size;
+    }
+    let msb = usize::BITS - 1 - size.leading_zeros();
+    size + (1 << (msb - SL_SHIFT)) - 1
+}
+
+#[inline(always)]
+unsafe fn size_of_block(block: *const Block) -> usize {
+    unsafe {
+        (*block).size & !FREE
+    }
+}
+
+#[inline(always)]
+unsafe fn set_size(block: *mut Block, size: usize) {
+    unsafe {
+        (*block).size = size | ((*block).size & FREE);
+    }
+}
+
+#[inline(always)]
+unsafe fn is_free(block: *const Block) -> bool {
+    unsafe {
+        (*block).size & FREE != 0
+    }
+}
+
+#[inline(always)]
+unsafe fn set_free(block: *mut Block, free: bool) {
+    unsafe {
+        (*block).size = size_of_block(block) | free as usize;
+    }
+}
+
+#[inline(always)]
+unsafe fn next_phys(block: *mut Block) -> *mut Block {
+    unsafe {
+        (block as *mut u8).add(size_of_block(block)) as *mut Block
+    }
+}
+
+#[inline(always)]
+unsafe fn payload(block: *mut Block) -> *mut u8 {
+    unsafe {
+        (block as *mut u8).add(HEADER)
+    }
+}
+
+#[inline(always)]
+unsafe fn from_payload(ptr: *mut u8) -> *mut Block {
+    unsafe {
+        ptr.sub(HEADER) as *mut Block
+    }
+}
+
+#[inline(always)]
+unsafe fn links(block: *mut Block) -> *mut Links {
+    unsafe {
+        payload(block) as *mut Links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static mut BUFFER: [u8; 1 << 20] = [0; 1 << 20];
+
+    #[test]
+    fn test_static_buffer() {
+        let buffer = unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) };
+        let mut tlsf = Tlsf::from_buffer(buffer);
+        let capacity = tlsf.capacity();
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let a = tlsf.allocate(layout);
+        let b = tlsf.allocate(Layout::from_size_align(4000, 256).unwrap());
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(b as usize % 256, 0);
+
+        let a = unsafe { tlsf.realloc(a, layout, 10_000) };
+        assert!(!a.is_null());
+        unsafe {
+            tlsf.dealloc(a, Layout::from_size_align(10_000, 8).unwrap());
+            tlsf.dealloc(b, Layout::from_size_align(4000, 256).unwrap());
+        }
+        assert_eq!(tlsf.used_bytes(), 0);
+
+        // Only fits if everything merged back together
+        let big = tlsf.allocate(Layout::from_size_align(capacity / 4 * 3, 8).unwrap());
+        assert!(!big.is_null());
+        assert!(tlsf.allocate(Layout::from_size_align(capacity / 2, 8).unwrap()).is_null());
+    }
+}
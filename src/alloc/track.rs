@@ -0,0 +1,141 @@
+//! Who allocated what, behind the `track` feature.
+//! Every tracked allocation records its caller through `#[track_caller]`,
+//! its size and a sequence number. The table lives in its own mapping, so it
+//! never shows up in the heap it's watching. Frees are a linear scan from the
+//! back, recent allocations die first. It's a leak hunter, not a profiler.
+
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::panic::Location;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use super::alloc::{map_chunk, unmap_chunk, PAGE_SIZE};
+
+/// One live allocation and where it came from.
+#[derive(Copy, Clone, Debug)]
+pub struct Site {
+    pub ptr: usize,
+    pub size: usize,
+    pub seq: u64, // Allocation order, 0 is the first tracked one
+    pub location: &'static Location<'static>,
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {} bytes at {:#x} from {}", self.seq, self.size, self.ptr, self.location)
+    }
+}
+
+pub(crate) struct Tracker {
+    sites: *mut Site,
+    len: usize,
+    cap: usize,
+    seq: u64,
+    lost: usize, // Records dropped because the table couldn't grow
+}
+
+impl Tracker {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self { sites: null_mut(), len: 0, cap: 0, seq: 0, lost: 0 }
+    }
+
+    pub(crate) fn record(&mut self, ptr: *mut u8, size: usize, location: &'static Location<'static>) {
+        let seq = self.seq;
+        self.seq += 1;
+        if self.len == self.cap && !self.grow() {
+            self.lost += 1;
+            return;
+        }
+        unsafe { self.sites.add(self.len).write(Site { ptr: ptr as usize, size, seq, location }) };
+        self.len += 1;
+    }
+
+    pub(crate) fn forget(&mut self, ptr: *mut u8) {
+        let sites = self.sites();
+        if let Some(i) = sites.iter().rposition(|site| site.ptr == ptr as usize) {
+            unsafe { *self.sites.add(i) = *self.sites.add(self.len - 1) };
+            self.len -= 1;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn sites(&self) -> &[Site] {
+        match self.len {
+            0 => &[],
+            len => unsafe { core::slice::from_raw_parts(self.sites, len) },
+        }
+    }
+
+    #[inline]
+    pub(crate) fn lost(&self) -> usize {
+        self.lost
+    }
+
+    /// Everything got freed behind our back, the table stays mapped.
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+        self.lost = 0;
+    }
+
+    // Doubles the table, starting at a page
+    #[cold]
+    fn grow(&mut self) -> bool {
+        let bytes = (self.cap * size_of::<Site>() * 2).max(PAGE_SIZE);
+        let sites = unsafe { map_chunk(bytes) } as *mut Site;
+        if sites.is_null() {
+            return false;
+        }
+        unsafe {
+            if !self.sites.is_null() {
+                copy_nonoverlapping(self.sites, sites, self.len);
+                unmap_chunk(self.sites as *mut u8, self.cap * size_of::<Site>());
+            }
+        }
+        self.sites = sites;
+        self.cap = bytes / size_of::<Site>();
+        true
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        if !self.sites.is_null() {
+            unsafe { unmap_chunk(self.sites as *mut u8, self.cap * size_of::<Site>()) };
+        }
+    }
+}
+
+/// Lists every site in `tracker`, nothing at all if it's empty.
+pub(crate) fn write_report<W: Write>(tracker: &Tracker, out: &mut W) -> fmt::Result {
+    let sites = tracker.sites();
+    if sites.is_empty() && tracker.lost() == 0 {
+        return Ok(());
+    }
+    let bytes: usize = sites.iter().map(|site| site.size).sum();
+    writeln!(out, "{} live allocations, {} bytes:", sites.len(), bytes)?;
+    for site in sites {
+        writeln!(out, "  leak {}", site)?;
+    }
+    if tracker.lost() > 0 {
+        writeln!(out, "  and {} more the table had no room for", tracker.lost())?;
+    }
+    Ok(())
+}
+
+// No std, so fd 2 by hand
+pub(crate) struct Stderr;
+
+impl Write for Stderr {
+    #[cfg(unix)]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { libc::write(2, s.as_ptr() as *const libc::c_void, s.len()) };
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        Ok(()) // Nowhere to write to
+    }
+}
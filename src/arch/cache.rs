@@ -0,0 +1,326 @@
+//! Every cache level the CPU will admit to, not just L1d.
+//! CPUID leaf 4 (0x8000_001D on AMD) is walked subleaf by subleaf until it
+//! runs out. If that's missing or talks nonsense, which VMs love to do, Linux
+//! gets one more try through `/sys/devices/system/cpu/cpu*/cache/index*`.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, __cpuid_count};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+// L1d, L1i, L2, L3 and then some, nobody ships more than this
+pub const MAX_CACHES: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CacheLevel {
+    pub level: u8,
+    pub kind: CacheKind,
+    pub size: u32,         // Bytes
+    pub line_size: u16,    // Bytes
+    pub sets: u32,
+    pub ways: u16,
+    pub shared_cores: u16, // Logical CPUs sharing this one
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CacheTopology {
+    caches: [CacheLevel; MAX_CACHES],
+    len: usize,
+}
+
+impl CacheTopology {
+    #[inline]
+    pub const fn empty() -> Self {
+        const NONE: CacheLevel = CacheLevel {
+            level: 0,
+            kind: CacheKind::Unified,
+            size: 0,
+            line_size: 0,
+            sets: 0,
+            ways: 0,
+            shared_cores: 0,
+        };
+        Self { caches: [NONE; MAX_CACHES], len: 0 }
+    }
+
+    /// CPUID first, sysfs second, empty if both come up dry.
+    pub fn detect() -> Self {
+        if let Some(topology) = Self::from_cpuid() {
+            return topology;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(topology) = Self::from_sysfs() {
+            return topology;
+        }
+        Self::empty()
+    }
+
+    /// Caches in the order the CPU listed them, usually L1d, L1i, L2, L3.
+    #[inline]
+    pub fn caches(&self) -> &[CacheLevel] {
+        &self.caches[..self.len]
+    }
+
+    /// Data (or unified) cache at `level`, what the allocator cares about.
+    #[inline]
+    pub fn data(&self, level: u8) -> Option<&CacheLevel> {
+        self.caches().iter().find(|c| c.level == level && c.kind != CacheKind::Instruction)
+    }
+
+    #[inline]
+    pub fn instruction(&self) -> Option<&CacheLevel> {
+        self.caches().iter().find(|c| c.kind == CacheKind::Instruction)
+    }
+
+    #[inline]
+    fn push(&mut self, cache: CacheLevel) {
+        if self.len < MAX_CACHES {
+            self.caches[self.len] = cache;
+            self.len += 1;
+        }
+    }
+
+    // Anything without a real L1d, or with a zero in it, isn't worth trusting
+    fn is_sane(&self) -> bool {
+        self.data(1).is_some()
+            && self.caches().iter().all(|c| c.size != 0 && c.line_size != 0 && c.ways != 0)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[allow(unused_unsafe)]
+    pub fn from_cpuid() -> Option<Self> {
+        let mut topology = Self::empty();
+        unsafe {
+            if __cpuid(0).eax >= 4 {
+                topology.walk_leaf(4);
+            }
+
+            // AMD keeps the same layout in an extended leaf, behind TOPOEXT
+            if topology.len == 0
+                && __cpuid(0x8000_0000).eax >= 0x8000_001d
+                && __cpuid(0x8000_0001).ecx & (1 << 22) != 0
+            {
+                topology.walk_leaf(0x8000_001d);
+            }
+        }
+        if topology.is_sane() { Some(topology) } else { None }
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    #[inline]
+    pub fn from_cpuid() -> Option<Self> {
+        None // No CPUID to ask
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[allow(unused_unsafe)]
+    fn walk_leaf(&mut self, leaf: u32) {
+        for subleaf in 0..MAX_CACHES as u32 {
+            let regs = unsafe { __cpuid_count(leaf, subleaf) };
+            let kind = match regs.eax & 0x1f {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => break, // 0 means no more caches
+            };
+
+            // Everything below is stored minus one
+            let line_size = (regs.ebx & 0xfff) + 1;
+            let partitions = ((regs.ebx >> 12) & 0x3ff) + 1;
+            let ways = ((regs.ebx >> 22) & 0x3ff) + 1;
+            let sets = regs.ecx.wrapping_add(1);
+
+            self.push(CacheLevel {
+                level: ((regs.eax >> 5) & 0x7) as u8,
+                kind,
+                size: ways.saturating_mul(partitions).saturating_mul(line_size).saturating_mul(sets),
+                line_size: line_size as u16,
+                sets,
+                ways: ways as u16,
+                shared_cores: (((regs.eax >> 14) & 0xfff) + 1) as u16,
+            });
+        }
+    }
+
+    /// Reads the first CPU that has a cache directory, which is cpu0
+    /// unless something odd is going on.
+    #[cfg(target_os = "linux")]
+    pub fn from_sysfs() -> Option<Self> {
+        // Nobody has this many CPUs without at least one of them answering
+        for cpu in 0..1024 {
+            let mut topology = Self::empty();
+            for index in 0..MAX_CACHES {
+                match sysfs::read_cache(cpu, index) {
+                    Some(cache) => topology.push(cache),
+                    None => break,
+                }
+            }
+            if topology.len == 0 && !sysfs::cpu_exists(cpu) {
+                return None; // Ran off the end of the CPUs
+            }
+            if topology.is_sane() {
+                return Some(topology);
+            }
+        }
+        None
+    }
+}
+
+// No std, so files are libc open/read/close into stack buffers
+#[cfg(target_os = "linux")]
+mod sysfs {
+    use core::fmt::{self, Write};
+
+    use super::{CacheKind, CacheLevel};
+
+    // Path or file contents, nul terminated for open()
+    struct Buf {
+        data: [u8; 128],
+        len: usize,
+    }
+
+    impl Buf {
+        #[inline]
+        const fn new() -> Self {
+            Self { data: [0; 128], len: 0 }
+        }
+
+        #[inline]
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.data[..self.len]).unwrap_or("").trim()
+        }
+    }
+
+    impl Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            // Leave room for the nul
+            if self.len + s.len() >= self.data.len() {
+                return Err(fmt::Error);
+            }
+            self.data[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    fn read(cpu: usize, index: usize, file: &str) -> Option<Buf> {
+        let mut path = Buf::new();
+        write!(path, "/sys/devices/system/cpu/cpu{}/cache/index{}/{}", cpu, index, file).ok()?;
+
+        let mut out = Buf::new();
+        unsafe {
+            let fd = libc::open(path.data.as_ptr() as *const libc::c_char, libc::O_RDONLY);
+            if fd < 0 {
+                return None;
+            }
+            let read = libc::read(fd, out.data.as_mut_ptr() as *mut libc::c_void, out.data.len() - 1);
+            libc::close(fd);
+            if read <= 0 {
+                return None;
+            }
+            out.len = read as usize;
+        }
+        Some(out)
+    }
+
+    fn read_u32(cpu: usize, index: usize, file: &str) -> Option<u32> {
+        read(cpu, index, file)?.as_str().parse().ok()
+    }
+
+    // "48K", "2048K", "32M", or plain bytes
+    fn parse_size(s: &str) -> Option<u32> {
+        let (digits, scale) = match s.as_bytes().last()? {
+            b'K' => (&s[..s.len() - 1], 1 << 10),
+            b'M' => (&s[..s.len() - 1], 1 << 20),
+            b'G' => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        digits.parse::<u32>().ok()?.checked_mul(scale)
+    }
+
+    // "0-3,8-11" is 8 CPUs
+    fn count_cpus(list: &str) -> u16 {
+        list.split(',')
+            .filter_map(|range| match range.split_once('-') {
+                Some((lo, hi)) => Some(hi.parse::<u16>().ok()?.checked_sub(lo.parse().ok()?)? + 1),
+                None => range.parse::<u16>().ok().map(|_| 1),
+            })
+            .sum()
+    }
+
+    pub(super) fn read_cache(cpu: usize, index: usize) -> Option<CacheLevel> {
+        let kind = match read(cpu, index, "type")?.as_str() {
+            "Data" => CacheKind::Data,
+            "Instruction" => CacheKind::Instruction,
+            "Unified" => CacheKind::Unified,
+            _ => return None,
+        };
+        let size = parse_size(read(cpu, index, "size")?.as_str())?;
+        let line_size = read_u32(cpu, index, "coherency_line_size").unwrap_or(64);
+        let ways = read_u32(cpu, index, "ways_of_associativity").unwrap_or(0);
+
+        // Some kernels (ARM mostly) leave sets out, it's implied anyway
+        let sets = read_u32(cpu, index, "number_of_sets")
+            .or_else(|| size.checked_div(ways * line_size))
+            .unwrap_or(0);
+
+        Some(CacheLevel {
+            level: read_u32(cpu, index, "level")? as u8,
+            kind,
+            size,
+            line_size: line_size as u16,
+            sets,
+            ways: ways as u16,
+            shared_cores: read(cpu, index, "shared_cpu_list")
+                .map(|list| count_cpus(list.as_str()))
+                .unwrap_or(1),
+        })
+    }
+
+    pub(super) fn cpu_exists(cpu: usize) -> bool {
+        let mut path = Buf::new();
+        if write!(path, "/sys/devices/system/cpu/cpu{}", cpu).is_err() {
+            return false;
+        }
+        unsafe { libc::access(path.data.as_ptr() as *const libc::c_char, libc::F_OK) == 0 }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_size() {
+            assert_eq!(parse_size("48K"), Some(48 << 10));
+            assert_eq!(parse_size("32M"), Some(32 << 20));
+            assert_eq!(parse_size("1024"), Some(1024));
+            assert_eq!(parse_size("1G"), Some(1 << 30));
+
+            assert_eq!(parse_size(""), None);
+            assert_eq!(parse_size("K"), None);
+            assert_eq!(parse_size("48k"), None); // The kernel only says K
+            assert_eq!(parse_size("4.5M"), None);
+            assert_eq!(parse_size("-1"), None);
+            assert_eq!(parse_size("8G"), None); // Past u32
+        }
+
+        #[test]
+        fn test_count_cpus() {
+            assert_eq!(count_cpus("0-3,8-11"), 8);
+            assert_eq!(count_cpus("0"), 1);
+            assert_eq!(count_cpus("0,2,4-5"), 4);
+
+            // Whatever doesn't parse counts for nothing
+            assert_eq!(count_cpus(""), 0);
+            assert_eq!(count_cpus("3-1"), 0);
+            assert_eq!(count_cpus("0-3,x,5-"), 4);
+        }
+    }
+}
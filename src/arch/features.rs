@@ -0,0 +1,197 @@
+//! What the CPU can do, and what the OS lets it do.
+//! CPUID says what the silicon has, XGETBV says whether the OS saves the
+//! wide registers on a context switch. AVX without the second half is a
+//! SIGILL waiting to happen. Detected once, then it's a relaxed load.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use core::arch::asm;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, __cpuid_count};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Top bit says the static has been filled in, no feature lives there
+const DETECTED: u32 = 1 << 31;
+
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+
+/// Bitset of runtime CPU features, see `CpuFeatures::get()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CpuFeatures(u32);
+
+impl CpuFeatures {
+    pub const SSE: Self = Self(1 << 0);
+    pub const SSE2: Self = Self(1 << 1);
+    pub const SSE3: Self = Self(1 << 2);
+    pub const SSSE3: Self = Self(1 << 3);
+    pub const SSE4_1: Self = Self(1 << 4);
+    pub const SSE4_2: Self = Self(1 << 5);
+    pub const POPCNT: Self = Self(1 << 6);
+    pub const AVX: Self = Self(1 << 7);
+    pub const AVX2: Self = Self(1 << 8);
+    pub const FMA: Self = Self(1 << 9);
+    pub const BMI1: Self = Self(1 << 10);
+    pub const BMI2: Self = Self(1 << 11);
+    pub const LZCNT: Self = Self(1 << 12);
+    pub const AVX512F: Self = Self(1 << 13);
+    pub const AVX512BW: Self = Self(1 << 14);
+    pub const AVX512VL: Self = Self(1 << 15);
+    pub const AVX512VPOPCNTDQ: Self = Self(1 << 16);
+
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// True if every feature in `other` is here.
+    #[inline(always)]
+    pub const fn has(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[inline]
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Cached features, detected on the first call. Racing threads both
+    /// detect and store the same answer, no harm done.
+    #[inline(always)]
+    pub fn get() -> Self {
+        let bits = FEATURES.load(Ordering::Relaxed);
+        if bits & DETECTED != 0 {
+            return Self(bits & !DETECTED);
+        }
+        Self::detect_and_cache()
+    }
+
+    #[cold]
+    fn detect_and_cache() -> Self {
+        let features = Self::detect();
+        FEATURES.store(features.0 | DETECTED, Ordering::Relaxed);
+        features
+    }
+
+    /// Asks the CPU every time, use `get()` unless you like CPUID latency.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[allow(unused_unsafe)]
+    pub fn detect() -> Self {
+        let mut features = Self::empty();
+        unsafe {
+            let max_leaf = __cpuid(0).eax;
+            if max_leaf < 1 {
+                return features;
+            }
+
+            let leaf1 = __cpuid(1);
+            let leaf7 = if max_leaf >= 7 { __cpuid_count(7, 0) } else { core::mem::zeroed() };
+            let ext = if __cpuid(0x8000_0000).eax >= 0x8000_0001 {
+                __cpuid(0x8000_0001).ecx
+            } else {
+                0
+            };
+
+            // Which register files the OS saves, nothing if it never turned on XSAVE
+            let xcr0 = if leaf1.ecx & (1 << 27) != 0 { xgetbv(0) } else { 0 };
+            let os_avx = xcr0 & 0b110 == 0b110; // XMM and YMM state
+            let os_avx512 = os_avx && xcr0 & 0b1110_0000 == 0b1110_0000; // Opmask, ZMM lo and hi
+
+            let mut set = |bit: bool, feature: Self| {
+                if bit {
+                    features = features.with(feature);
+                }
+            };
+
+            set(leaf1.edx & (1 << 25) != 0, Self::SSE);
+            set(leaf1.edx & (1 << 26) != 0, Self::SSE2);
+            set(leaf1.ecx & (1 << 0) != 0, Self::SSE3);
+            set(leaf1.ecx & (1 << 9) != 0, Self::SSSE3);
+            set(leaf1.ecx & (1 << 19) != 0, Self::SSE4_1);
+            set(leaf1.ecx & (1 << 20) != 0, Self::SSE4_2);
+            set(leaf1.ecx & (1 << 23) != 0, Self::POPCNT);
+            set(leaf7.ebx & (1 << 3) != 0, Self::BMI1);
+            set(leaf7.ebx & (1 << 8) != 0, Self::BMI2);
+            set(ext & (1 << 5) != 0, Self::LZCNT);
+
+            set(os_avx && leaf1.ecx & (1 << 28) != 0, Self::AVX);
+            set(os_avx && leaf1.ecx & (1 << 12) != 0, Self::FMA);
+            set(os_avx && leaf7.ebx & (1 << 5) != 0, Self::AVX2);
+
+            set(os_avx512 && leaf7.ebx & (1 << 16) != 0, Self::AVX512F);
+            set(os_avx512 && leaf7.ebx & (1 << 30) != 0, Self::AVX512BW);
+            set(os_avx512 && leaf7.ebx & (1 << 31) != 0, Self::AVX512VL);
+            set(os_avx512 && leaf7.ecx & (1 << 14) != 0, Self::AVX512VPOPCNTDQ);
+        }
+        features
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    #[inline]
+    pub fn detect() -> Self {
+        Self::empty() // Nothing we know how to use
+    }
+}
+
+// The intrinsic wants target_feature = "xsave" on the caller, asm doesn't care
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline]
+unsafe fn xgetbv(register: u32) -> u64 {
+    unsafe {
+        let (lo, hi): (u32, u32);
+        asm!("xgetbv", in("ecx") register, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+        (hi as u64) << 32 | lo as u64
+    }
+}
+
+#[cfg(all(test, any(target_arch = "x86", target_arch = "x86_64")))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::arch::is_x86_feature_detected;
+
+    #[test]
+    fn test_agrees_with_std() {
+        let features = CpuFeatures::get();
+        let expected = [
+            (CpuFeatures::SSE, is_x86_feature_detected!("sse")),
+            (CpuFeatures::SSE2, is_x86_feature_detected!("sse2")),
+            (CpuFeatures::SSE3, is_x86_feature_detected!("sse3")),
+            (CpuFeatures::SSSE3, is_x86_feature_detected!("ssse3")),
+            (CpuFeatures::SSE4_1, is_x86_feature_detected!("sse4.1")),
+            (CpuFeatures::SSE4_2, is_x86_feature_detected!("sse4.2")),
+            (CpuFeatures::POPCNT, is_x86_feature_detected!("popcnt")),
+            (CpuFeatures::AVX, is_x86_feature_detected!("avx")),
+            (CpuFeatures::AVX2, is_x86_feature_detected!("avx2")),
+            (CpuFeatures::FMA, is_x86_feature_detected!("fma")),
+            (CpuFeatures::BMI1, is_x86_feature_detected!("bmi1")),
+            (CpuFeatures::BMI2, is_x86_feature_detected!("bmi2")),
+            (CpuFeatures::LZCNT, is_x86_feature_detected!("lzcnt")),
+            (CpuFeatures::AVX512F, is_x86_feature_detected!("avx512f")),
+            (CpuFeatures::AVX512BW, is_x86_feature_detected!("avx512bw")),
+            (CpuFeatures::AVX512VL, is_x86_feature_detected!("avx512vl")),
+            (CpuFeatures::AVX512VPOPCNTDQ, is_x86_feature_detected!("avx512vpopcntdq")),
+        ];
+        for (feature, std_says) in expected {
+            assert_eq!(features.has(feature), std_says, "{:?}", feature);
+        }
+        assert_eq!(features, CpuFeatures::detect());
+    }
+
+    #[test]
+    fn test_second_call_is_cached() {
+        let first = CpuFeatures::get();
+        let stored = FEATURES.load(Ordering::Relaxed);
+        assert_ne!(stored & DETECTED, 0);
+        assert_eq!(stored & !DETECTED, first.bits());
+        // Served from the static, which stays what the first call stored
+        assert_eq!(CpuFeatures::get(), first);
+        assert_eq!(FEATURES.load(Ordering::Relaxed), stored);
+    }
+}
@@ -0,0 +1,13 @@
+mod cache;
+mod features;
+
+pub use cache::{CacheKind, CacheLevel, CacheTopology, MAX_CACHES};
+pub use features::CpuFeatures;
+
+/// Cached runtime check, `cpu_has!(AVX2)` instead of `is_x86_feature_detected!`.
+#[macro_export]
+macro_rules! cpu_has {
+    ($feature:ident) => {
+        $crate::arch::CpuFeatures::get().has($crate::arch::CpuFeatures::$feature)
+    };
+}
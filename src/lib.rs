@@ -1,6 +1,13 @@
-#![feature(core_intrinsics, rustc_private)]
+#![feature(allocator_api, rustc_private)]
 #![allow(internal_features)]
+#![cfg_attr(feature = "track", feature(closure_track_caller))]
 #![no_std]
 
 #[cfg(not(windows))]
 extern crate libc;
+// Not as `alloc`, that name's taken. `vec::Vec` defaults to its `Global`
+extern crate alloc as rust_alloc;
+
+pub mod alloc;
+pub mod arch;
+pub mod vec;
@@ -0,0 +1,303 @@
+//! Bitmap operations on a 1-bit `Vec<bool>`, counting and finding ones.
+//!
+//! Counting takes the widest popcount the CPU has: VPOPCNTDQ eight words at
+//! a time, the AVX2 nibble lookup four at a time, scalar POPCNT otherwise.
+//! Off x86_64 it's `count_ones` all the way.
+//! Bits past the length in the last word are whatever was left there, so
+//! everything here masks them off first.
+
+use core::alloc::Allocator;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{ self, _lzcnt_u64, _tzcnt_u64 };
+
+use rust_alloc::alloc::Global;
+
+use crate::arch::CpuFeatures;
+use crate::vec::{ structs::Vec, unsafe_impls::words_for };
+
+impl<A: Allocator> Vec<bool, A> {
+    /// Set bits, SIMD over the whole words.
+    #[inline]
+    pub fn count_ones(&self) -> usize {
+        self.count_ones_with(CpuFeatures::get())
+    }
+
+    // Same, but only with what `features` has, so a test can pick the path
+    pub(crate) fn count_ones_with(&self, features: CpuFeatures) -> usize {
+        self.check_bitmap();
+        let full = self.len / 64;
+        let ones = unsafe { popcount_words(features, self.data, full) };
+        ones + popcount_with(features, self.bitmap_word(full)) as usize
+    }
+
+    #[inline]
+    pub fn count_zeros(&self) -> usize {
+        self.len - self.count_ones()
+    }
+
+    /// Position of the lowest set bit, None if there isn't one.
+    #[inline]
+    pub fn first_one(&self) -> Option<usize> {
+        self.iter_ones().next()
+    }
+
+    /// Position of the highest set bit, None if there isn't one.
+    pub fn last_one(&self) -> Option<usize> {
+        self.check_bitmap();
+        (0..words_for(self.len)).rev().find_map(|i| {
+            let word = self.bitmap_word(i);
+            (word != 0).then(|| i * 64 + 63 - leading_zeros(word) as usize)
+        })
+    }
+
+    /// Positions of the set bits, lowest first, a word at a time.
+    #[inline]
+    pub fn iter_ones(&self) -> IterOnes<'_, A> {
+        self.check_bitmap();
+        IterOnes { bits: self, word: 0, current: self.bitmap_word(0) }
+    }
+
+    // Word `i`, bits past the length cleared and 0 past the end
+    #[inline(always)]
+    pub(crate) fn bitmap_word(&self, i: usize) -> u64 {
+        if i >= words_for(self.len) {
+            return 0;
+        }
+        let word = unsafe { *self.data.add(i) };
+        if (i + 1) * 64 > self.len && self.len % 64 != 0 {
+            return word & ((1 << (self.len % 64)) - 1);
+        }
+        word
+    }
+
+    #[inline(always)]
+    fn check_bitmap(&self) {
+        if self.bit_width != 1 {
+            panic!("Bitmap exploded: bit_width is {}, it only works on 1", self.bit_width);
+        }
+    }
+}
+
+/// Set bit positions of a `Vec<bool>`, see `Vec::iter_ones`.
+pub struct IterOnes<'a, A: Allocator = Global> {
+    bits: &'a Vec<bool, A>,
+    word: usize,  // Index of `current`
+    current: u64, // Ones of that word not handed out yet
+}
+
+impl<A: Allocator> Iterator for IterOnes<'_, A> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        while self.current == 0 {
+            self.word += 1;
+            if self.word >= words_for(self.bits.len) {
+                return None;
+            }
+            self.current = self.bits.bitmap_word(self.word);
+        }
+        let bit = trailing_zeros(self.current) as usize;
+        self.current &= self.current - 1; // Drop the lowest one
+        Some(self.word * 64 + bit)
+    }
+}
+
+// TZCNT and LZCNT where the macros have them, plain Rust anywhere else
+#[inline(always)]
+fn trailing_zeros(word: u64) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    return count_trailing_zeros!(word);
+    #[cfg(not(target_arch = "x86_64"))]
+    word.trailing_zeros()
+}
+
+#[inline(always)]
+fn leading_zeros(word: u64) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    return count_leading_zeros!(word);
+    #[cfg(not(target_arch = "x86_64"))]
+    word.leading_zeros()
+}
+
+// POPCNT when the CPU has it, the bit-twiddling fallback when it doesn't
+#[inline(always)]
+pub(crate) fn popcount(word: u64) -> u32 {
+    popcount_with(CpuFeatures::get(), word)
+}
+
+#[inline(always)]
+fn popcount_with(features: CpuFeatures, word: u64) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if features.has(CpuFeatures::POPCNT) {
+        return unsafe { x86_64::_popcnt64(word as i64) as u32 };
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = features;
+    word.count_ones()
+}
+
+/// Ones in `count` words from `words`, as wide as `features` goes.
+pub(crate) unsafe fn popcount_words(features: CpuFeatures, words: *const u64, count: usize) -> usize {
+    let (mut ones, done) = unsafe { popcount_wide(features, words, count) };
+    for i in done..count {
+        ones += popcount_with(features, unsafe { *words.add(i) }) as usize; // Whatever the vectors left over
+    }
+    ones
+}
+
+// Ones in the words the widest vectors covered, and how many words that was
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn popcount_wide(features: CpuFeatures, words: *const u64, count: usize) -> (usize, usize) {
+    if features.has(CpuFeatures::AVX512F.with(CpuFeatures::AVX512VPOPCNTDQ)) {
+        return unsafe { popcount_avx512(words, count) };
+    }
+    if features.has(CpuFeatures::AVX2) {
+        return unsafe { popcount_avx2(words, count) };
+    }
+    (0, 0)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+unsafe fn popcount_wide(_features: CpuFeatures, _words: *const u64, _count: usize) -> (usize, usize) {
+    (0, 0) // No vectors here, the scalar loop gets all of it
+}
+
+// Ones in whole groups of 8 words, and how many words that covered
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f,avx512vpopcntdq")]
+#[allow(clippy::incompatible_msrv)] // Stable since 1.89, nothing here builds on a stable 1.85 anyway
+unsafe fn popcount_avx512(words: *const u64, count: usize) -> (usize, usize) {
+    unsafe {
+        let mut total = x86_64::_mm512_setzero_si512();
+        let mut i = 0;
+        while i + 8 <= count {
+            let chunk = x86_64::_mm512_loadu_si512(words.add(i) as *const _);
+            total = x86_64::_mm512_add_epi64(total, x86_64::_mm512_popcnt_epi64(chunk));
+            i += 8;
+        }
+        (x86_64::_mm512_reduce_add_epi64(total) as usize, i)
+    }
+}
+
+// Same in groups of 4, each nibble looked up in a 16 entry table
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn popcount_avx2(words: *const u64, count: usize) -> (usize, usize) {
+    unsafe {
+        let table = x86_64::_mm256_setr_epi8(
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        );
+        let nibble = x86_64::_mm256_set1_epi8(0x0f);
+        let zero = x86_64::_mm256_setzero_si256();
+        let mut total = zero;
+        let mut i = 0;
+        while i + 4 <= count {
+            let chunk = x86_64::_mm256_loadu_si256(words.add(i) as *const _);
+            let low = x86_64::_mm256_and_si256(chunk, nibble);
+            let high = x86_64::_mm256_and_si256(x86_64::_mm256_srli_epi16(chunk, 4), nibble);
+            let bytes = x86_64::_mm256_add_epi8(
+                x86_64::_mm256_shuffle_epi8(table, low),
+                x86_64::_mm256_shuffle_epi8(table, high),
+            );
+            // Byte counts top out at 8, summing them into u64 lanes right away can't overflow
+            total = x86_64::_mm256_add_epi64(total, x86_64::_mm256_sad_epu8(bytes, zero));
+            i += 4;
+        }
+        let lanes: [u64; 4] = core::mem::transmute(total);
+        (lanes.iter().sum::<u64>() as usize, i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    // Bit i is whatever `f(i)` says
+    fn bits(len: usize, f: impl Fn(usize) -> bool) -> Vec<bool> {
+        let mut bits = Vec::with_capacity(len, 1);
+        for i in 0..len {
+            bits.push(f(i));
+        }
+        bits
+    }
+
+    // Every popcount path, widest first, the ones this CPU can't run left out
+    fn paths() -> impl Iterator<Item = (&'static str, CpuFeatures)> {
+        let vpopcntdq = CpuFeatures::AVX512F.with(CpuFeatures::AVX512VPOPCNTDQ);
+        [
+            ("avx512 vpopcntdq", vpopcntdq.with(CpuFeatures::AVX2).with(CpuFeatures::POPCNT)),
+            ("avx2", CpuFeatures::AVX2.with(CpuFeatures::POPCNT)),
+            ("popcnt", CpuFeatures::POPCNT),
+            ("scalar", CpuFeatures::empty()),
+        ]
+        .into_iter()
+        .filter(|&(_, features)| CpuFeatures::get().has(features))
+    }
+
+    // Everything against a bit by bit scan, counted down every path
+    fn check(bits: &Vec<bool>) {
+        let ones: std::vec::Vec<usize> = (0..bits.len()).filter(|&i| bits.get(i) == 1).collect();
+        for (path, features) in paths() {
+            assert_eq!(bits.count_ones_with(features), ones.len(), "{} count_ones at {}", path, bits.len());
+        }
+        assert_eq!(bits.count_ones(), ones.len(), "count_ones at {}", bits.len());
+        assert_eq!(bits.count_zeros(), bits.len() - ones.len());
+        assert_eq!(bits.first_one(), ones.first().copied());
+        assert_eq!(bits.last_one(), ones.last().copied());
+        assert!(bits.iter_ones().eq(ones.iter().copied()), "iter_ones at {}", bits.len());
+    }
+
+    #[test]
+    fn test_against_scalar() {
+        // Off by a bit or a word from every vector width
+        let lengths = [0, 1, 63, 65, 130, 255, 257, 300, 511, 513, 700, 1000, 1100, 4099];
+        for len in lengths {
+            check(&bits(len, |_| false));
+            check(&bits(len, |_| true));
+            check(&bits(len, |i| i % 3 == 0));
+            check(&bits(len, |i| (i * 7919) % 13 < 4));
+            check(&bits(len, |i| i + 1 == len));
+            check(&bits(len, |i| i == 0));
+        }
+    }
+
+    #[test]
+    fn test_ignores_bits_past_len() {
+        // The tail of the last word keeps its ones, none of them may count
+        for len in [1, 63, 65, 257, 513, 1000] {
+            let mut tail = bits(len + 7, |i| i >= len);
+            tail.len = len;
+            assert_eq!(tail.count_ones(), 0);
+            assert_eq!(tail.first_one(), None);
+            assert_eq!(tail.last_one(), None);
+            assert_eq!(tail.iter_ones().next(), None);
+        }
+    }
+
+    #[test]
+    fn test_every_path_runs() {
+        // 12 words, AVX-512 takes 8 of them and AVX2 all 12, so it shows which one ran
+        let words = [u64::MAX, 0, 0x5555_5555_5555_5555, 1 << 63, 0xff, 3, 0, u64::MAX, 7, 0, 1, !1];
+        let expected: usize = words.iter().map(|w| w.count_ones() as usize).sum();
+        let mut ran = 0;
+        for (path, features) in paths() {
+            let wide = unsafe { popcount_wide(features, words.as_ptr(), words.len()) };
+            let covered = match path {
+                _ if !cfg!(target_arch = "x86_64") => 0,
+                "avx512 vpopcntdq" => 8,
+                "avx2" => 12,
+                _ => 0, // All of it scalar
+            };
+            assert_eq!(wide.1, covered, "{} covered", path);
+            assert_eq!(unsafe { popcount_words(features, words.as_ptr(), words.len()) }, expected, "{}", path);
+            ran += 1;
+        }
+        assert!(ran >= 1, "the scalar path always runs");
+    }
+}
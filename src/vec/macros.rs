@@ -3,7 +3,6 @@
 //! This module provides highly optimized implementations using:
 //! - CPU-specific SIMD instructions (AVX-512, AVX2, SSE)
 #[allow(unused_imports)]
-
 #[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::{
     // AVX-512 instructions
@@ -46,10 +45,6 @@ use core::arch::x86_64::{
     _mm_clflush, // Cache line flush
     _mm_lfence, // Load fence
     // _mm_sfence, // Store fence
-    // New feature detection helpers
-    __cpuid,
-    __cpuid_count,
-    __get_cpuid_max,
 };
 
 /// Architecture-specific optimized memory copy.
@@ -99,12 +94,12 @@ macro_rules! arch_specific_compare {
     ($data:expr, $offset:expr) => {
         #[cfg(target_arch = "x86_64")]
         {
-            if core::is_x86_feature_detected!("avx512f") {
+            if $crate::cpu_has!(AVX512F) {
                 unsafe_or_explode!({
                     let vec = _mm512_loadu_si512($data.add($offset) as *const _);
                     _mm512_movepi8_mask(vec) as i32
                 }, "Failed to perform AVX-512 memory comparison")
-            } else if core::is_x86_feature_detected!("avx2") {
+            } else if $crate::cpu_has!(AVX2) {
                 unsafe_or_explode!({
                     let vec = _mm256_loadu_si256($data.add($offset) as *const _);
                     _mm256_movemask_epi8(vec)
@@ -135,7 +130,7 @@ macro_rules! arch_specific_compare {
 macro_rules! arch_specific_prefetch {
     ($ptr:expr, $rw:expr, $locality:expr) => {
         #[cfg(target_arch = "x86_64")]
-        if is_x86_feature_detected!("sse") {
+        if $crate::cpu_has!(SSE) {
             unsafe_or_explode!({
                 match ($rw, $locality) {
                     (0, 0) => _mm_prefetch($ptr, _MM_HINT_T0),
@@ -155,7 +150,7 @@ macro_rules! arch_specific_memcpy {
     ($dst:expr, $src:expr, $size:expr) => {
         #[cfg(target_arch = "x86_64")]
         unsafe_or_explode!({
-            if is_x86_feature_detected!("avx512f") {
+            if $crate::cpu_has!(AVX512F) {
                 let mut i = 0;
                 while i + 64 <= $size {
                     let tmp = _mm512_loadu_si512($src.add(i) as *const _);
@@ -207,12 +202,12 @@ macro_rules! arch_specific_memset {
 
                 if ($count >= 512) {
                     for i in (offset..$count).step_by(512) {
-                        intrinsics::prefetch_write_data(($dst as *mut u8).add(i + 512), 3);
-                        intrinsics::prefetch_write_data(($dst as *mut u8).add(i + 576), 2);
+                        intrinsics::prefetch_write_data::<u8, 3>(($dst as *mut u8).add(i + 512));
+                        intrinsics::prefetch_write_data::<u8, 2>(($dst as *mut u8).add(i + 576));
                     }
                 }
 
-                if core::is_x86_feature_detected!("avx512f") {
+                if $crate::cpu_has!(AVX512F) {
                     let value = _mm512_set1_epi8($val as i8);
                     let use_nontemporal = $count >= 4096;
                     
@@ -272,31 +267,10 @@ macro_rules! arch_specific_cache_control {
     };
 }
 
-// Add new macro for CPU feature detection
+// CPU feature detection, cached in arch so nobody pays for CPUID twice
 macro_rules! arch_specific_cpu_features {
     () => {
-        #[cfg(target_arch = "x86_64")]
-        unsafe_or_explode!({
-            let (max_level, vendor_id) = __get_cpuid_max(0);
-            let mut features = Vec::new();
-            
-            if max_level >= 1 {
-                let info = __cpuid(1);
-                if (info.ecx & (1 << 28)) != 0 { features.push("AVX"); }
-                if (info.ecx & (1 << 20)) != 0 { features.push("SSE4.2"); }
-                // Add more feature detection as needed
-            }
-            
-            if max_level >= 7 {
-                let info = __cpuid_count(7, 0);
-                if (info.ebx & (1 << 5)) != 0 { features.push("AVX2"); }
-                if (info.ebx & (1 << 16)) != 0 { features.push("AVX512F"); }
-            }
-            
-            features
-        }, "Failed to detect CPU features");
-        #[cfg(not(target_arch = "x86_64"))]
-        Vec::new()
+        $crate::arch::CpuFeatures::get()
     };
 }
 
@@ -305,7 +279,7 @@ macro_rules! arch_specific_numeric_ops {
     (fmadd $a:expr, $b:expr, $c:expr) => {
         #[cfg(target_arch = "x86_64")]
         unsafe_or_explode!({
-            if is_x86_feature_detected!("fma") {
+            if $crate::cpu_has!(FMA) {
                 _mm256_fmadd_pd($a, $b, $c)
             } else {
                 // Fallback implementation
@@ -1,6 +1,5 @@
-#![allow(internal_features, unstable_features)]
-#![feature(allocator_api, core_intrinsics, stdarch_x86_avx512)]
 #![allow(unused_unsafe)]
+#![allow(clippy::macro_metavars_in_unsafe)] // `unsafe_or_explode!` putting `$expr` in unsafe is the whole job
 
 #[macro_use]
 #[allow(unused_macros)]
@@ -14,6 +13,20 @@ mod structs;
 mod traits;
 mod unsafe_impls;
 mod safe_impls;
+mod bitmap;
+mod rank_select;
 
-pub use structs::Vec;
-pub use traits::{ ToBits, FromBits, OrExplode };
+pub use bitmap::IterOnes;
+pub use rank_select::{ RankSelect, SELECT_SAMPLE };
+pub use structs::{ Vec, SIMD_ALIGN, BitVecError, InstructionSet };
+pub use traits::{ ToBits, FromBits, OrExplode, Packed, BareSimd, BareMath };
+pub use utils::{
+    check_simd_support,
+    simd_splat,
+    simd_zero,
+    simd_abs,
+    simd_min,
+    simd_max,
+    simd_blend,
+    simd_select,
+};
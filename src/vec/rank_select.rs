@@ -0,0 +1,246 @@
+//! Rank and select over a 1-bit `Vec<bool>`.
+//!
+//! Superblocks of 512 bits keep the ones before them in one `u64`, and the
+//! counts of their first 1..7 words, 9 bits each, in another. `rank1` is two
+//! lookups and a POPCNT. `select1` starts from a sample taken every
+//! `SELECT_SAMPLE` ones, binary searches superblocks up to the next sample,
+//! then walks at most 8 words.
+//!
+//! The index borrows the vector, so it can't see a stale one. Mutating the
+//! vector ends the borrow; build a fresh index afterwards.
+
+use core::alloc::Allocator;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64;
+
+use rust_alloc::alloc::Global;
+
+#[cfg(target_arch = "x86_64")]
+use crate::arch::CpuFeatures;
+use crate::vec::{ bitmap::popcount, structs::Vec, unsafe_impls::words_for };
+
+const SUPER_WORDS: usize = 8; // 512 bits per superblock
+const INNER_BITS: usize = 9; // Enough for 7 * 64 ones
+
+/// Ones between two select samples.
+pub const SELECT_SAMPLE: usize = 4096;
+
+pub struct RankSelect<'a, A: Allocator = Global> {
+    bits: &'a Vec<bool, A>,
+    counts: Vec<u64>,  // Per superblock: ones before it, then the packed in-superblock counts
+    samples: Vec<u64>, // Superblock holding one number k * SELECT_SAMPLE, for every k
+    ones: usize,
+}
+
+impl<'a, A: Allocator> RankSelect<'a, A> {
+    /// One pass over `bits`, about 1/4 of its size again in counts.
+    pub fn new(bits: &'a Vec<bool, A>) -> Self {
+        if bits.bit_width != 1 {
+            panic!("RankSelect exploded: bit_width is {}, it only works on 1", bits.bit_width);
+        }
+        let words = words_for(bits.len);
+        let supers = words.div_ceil(SUPER_WORDS) + 1; // One past the end, so rank1(len) has one
+        let mut index = Self {
+            bits,
+            counts: Vec::with_capacity(supers * 2, 64),
+            samples: Vec::new(64, 0, 64),
+            ones: 0,
+        };
+
+        let mut next_sample = 0;
+        for superblock in 0..supers {
+            let mut inner = 0;
+            let mut packed = 0;
+            for offset in 0..SUPER_WORDS {
+                if offset > 0 {
+                    packed |= (inner as u64) << (INNER_BITS * (offset - 1));
+                }
+                inner += popcount(index.word(superblock * SUPER_WORDS + offset)) as usize;
+            }
+            index.counts.push(index.ones as u64);
+            index.counts.push(packed);
+            index.ones += inner;
+            while next_sample < index.ones {
+                index.samples.push(superblock as u64);
+                next_sample += SELECT_SAMPLE;
+            }
+        }
+        index
+    }
+
+    #[inline(always)]
+    pub fn ones(&self) -> usize {
+        self.ones
+    }
+
+    /// Ones in `0..i`, `i` can be the length itself.
+    #[inline]
+    pub fn rank1(&self, i: usize) -> usize {
+        if i > self.bits.len {
+            panic!("Rank exploded: position {} but the length is {}", i, self.bits.len);
+        }
+        let word = i / 64;
+        let superblock = word / SUPER_WORDS;
+        let mut rank = self.before(superblock) + self.inner(superblock, word % SUPER_WORDS);
+        if i % 64 != 0 {
+            rank += popcount(self.word(word) & ((1 << (i % 64)) - 1)) as usize;
+        }
+        rank
+    }
+
+    /// Zeros in `0..i`.
+    #[inline]
+    pub fn rank0(&self, i: usize) -> usize {
+        i - self.rank1(i)
+    }
+
+    /// Position of the `k`th one, counting from 0. None if there are `k` or fewer.
+    pub fn select1(&self, k: usize) -> Option<usize> {
+        if k >= self.ones {
+            return None;
+        }
+
+        // Last superblock with at most k ones before it, between two samples
+        let sample = k / SELECT_SAMPLE;
+        let mut low = self.samples.get_unchecked(sample) as usize;
+        let mut high = if sample + 1 < self.samples.len() {
+            self.samples.get_unchecked(sample + 1) as usize
+        } else {
+            self.counts.len() / 2 - 1
+        };
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if self.before(mid) <= k {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+
+        let mut rest = k - self.before(low);
+        let mut offset = SUPER_WORDS - 1;
+        while self.inner(low, offset) > rest {
+            offset -= 1;
+        }
+        rest -= self.inner(low, offset);
+        let word = low * SUPER_WORDS + offset;
+        Some(word * 64 + select_in_word(self.word(word), rest))
+    }
+
+    #[inline(always)]
+    fn before(&self, superblock: usize) -> usize {
+        self.counts.get_unchecked(superblock * 2) as usize
+    }
+
+    // Ones in the words of `superblock` ahead of `offset`
+    #[inline(always)]
+    fn inner(&self, superblock: usize, offset: usize) -> usize {
+        if offset == 0 {
+            return 0;
+        }
+        let packed = self.counts.get_unchecked(superblock * 2 + 1);
+        ((packed >> (INNER_BITS * (offset - 1))) & ((1 << INNER_BITS) - 1)) as usize
+    }
+
+    #[inline(always)]
+    fn word(&self, i: usize) -> u64 {
+        self.bits.bitmap_word(i)
+    }
+}
+
+impl<A: Allocator> Vec<bool, A> {
+    /// Builds a `RankSelect` over this vector, see there.
+    #[inline]
+    pub fn rank_select(&self) -> RankSelect<'_, A> {
+        RankSelect::new(self)
+    }
+}
+
+// Position of the `n`th set bit, PDEP does it in one go on BMI2
+#[inline(always)]
+fn select_in_word(mut word: u64, n: usize) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    if CpuFeatures::get().has(CpuFeatures::BMI2) {
+        return unsafe { x86_64::_pdep_u64(1 << n, word).trailing_zeros() as usize };
+    }
+    for _ in 0..n {
+        word &= word - 1; // Drop the lowest one
+    }
+    word.trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bit i is whatever `f(i)` says
+    fn bits(len: usize, f: impl Fn(usize) -> bool) -> Vec<bool> {
+        let mut bits = Vec::with_capacity(len, 1);
+        for i in 0..len {
+            bits.push(f(i));
+        }
+        bits
+    }
+
+    // Every rank and select against a plain scan
+    fn check(bits: &Vec<bool>) {
+        let index = bits.rank_select();
+        let mut ones = 0;
+        for i in 0..bits.len() {
+            assert_eq!(index.rank1(i), ones, "rank1({})", i);
+            assert_eq!(index.rank0(i), i - ones, "rank0({})", i);
+            if bits.get(i) == 1 {
+                assert_eq!(index.select1(ones), Some(i), "select1({})", ones);
+                ones += 1;
+            }
+        }
+        assert_eq!(index.rank1(bits.len()), ones);
+        assert_eq!(index.ones(), ones);
+        assert_eq!(index.select1(ones), None);
+    }
+
+    #[test]
+    fn test_empty() {
+        let bits = Vec::<bool>::new(1, 0, 64);
+        let index = bits.rank_select();
+        assert_eq!(index.ones(), 0);
+        assert_eq!(index.rank1(0), 0);
+        assert_eq!(index.select1(0), None);
+    }
+
+    #[test]
+    fn test_superblock_edges() {
+        for len in [1, 63, 64, 511, 512, 513, 1000, 1024, 4096] {
+            check(&bits(len, |i| i % 3 == 0));
+        }
+
+        // rank1(len) on a whole number of superblocks, all of them full
+        let full = bits(1024, |_| true);
+        let index = full.rank_select();
+        assert_eq!(index.rank1(512), 512);
+        assert_eq!(index.rank1(1024), 1024);
+        assert_eq!(index.select1(1023), Some(1023));
+
+        // Only the very last bit
+        let last = bits(1024, |i| i == 1023);
+        let index = last.rank_select();
+        assert_eq!(index.rank1(1023), 0);
+        assert_eq!(index.rank1(1024), 1);
+        assert_eq!(index.select1(index.ones() - 1), Some(1023));
+        assert_eq!(index.select1(1), None);
+    }
+
+    #[test]
+    fn test_select_across_samples() {
+        let len = SELECT_SAMPLE * 5 + 77;
+        let sparse = bits(len, |i| i % 2 == 0 || i % 7 == 0);
+        check(&sparse);
+
+        let dense = bits(len, |_| true);
+        let index = dense.rank_select();
+        for k in [SELECT_SAMPLE - 1, SELECT_SAMPLE, SELECT_SAMPLE + 1, SELECT_SAMPLE * 5] {
+            assert_eq!(index.select1(k), Some(k));
+        }
+        assert_eq!(index.select1(index.ones() - 1), Some(len - 1));
+    }
+}
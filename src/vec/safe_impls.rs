@@ -3,15 +3,16 @@
 //! This module provides safe, non-architecture-specific implementations of standard
 //! collection traits and core functionality for the `Vec` type.
 
-use core::alloc::AllocError;
+use core::alloc::{ AllocError, Allocator };
 use core::cmp::Ordering;
-use core::marker::PhantomData;
 
-use crate::{ traits::{ ToBits }, structs::Vec };
+use rust_alloc::alloc::Global;
+
+use crate::vec::{ traits::{ Packed, ToBits }, structs::Vec, unsafe_impls::words_for };
 
 /// A collection of safe methods for the `Vec` type that provides
 /// bit-packed vector functionality with standard collection semantics.
-impl<T: ToBits> Vec<T> {
+impl<T: ToBits, A: Allocator> Vec<T, A> {
     /// Returns `true` if the vector contains no elements.
     #[inline(always)]
     pub fn is_empty(&self) -> bool {
@@ -39,34 +40,58 @@ impl<T: ToBits> Vec<T> {
         }
     }
 
-    /// Reserves capacity for at least `additional` more elements or explodes.
+    /// Reserves capacity for at least `additional` more elements. On failure
+    /// the vector is left exactly as it was.
     pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
-        if self.bit_capacity - self.len >= additional * self.bit_width {
-            Ok(())
-        } else {
-            let new_cap = self.len + additional * self.bit_width;
-            if new_cap <= (isize::MAX as usize) {
-                unsafe {
-                    self.reserve(new_cap / self.bit_width - self.len());
-                    Ok(())
-                }
-            } else {
-                Err(AllocError)
-            }
+        let needed = additional
+            .checked_mul(self.bit_width)
+            .and_then(|bits| bits.checked_add(self.len))
+            .filter(|&bits| bits <= isize::MAX as usize)
+            .ok_or(AllocError)?;
+        if needed <= self.bit_capacity {
+            return Ok(());
+        }
+
+        let new_capacity = needed.div_ceil(self.bit_width);
+        let new_data = self.try_alloc_buffer(new_capacity)?;
+        if !self.data.is_null() {
+            unsafe { core::ptr::copy_nonoverlapping(self.data, new_data, words_for(self.len)) };
+            self.dealloc_buffer();
         }
+        self.data = new_data;
+        self.bit_capacity = new_capacity * self.bit_width;
+        Ok(())
     }
 
-    /// New method to allow calling `reserve` in try_reserve
+    /// Reserves capacity for at least `additional` more elements or explodes.
     pub fn reserve(&mut self, additional: usize) {
-        unsafe_or_explode!(self.try_reserve(additional), "Reserve exploded");
+        if self.try_reserve(additional).is_err() {
+            panic!("Reserve exploded: no room for {} more elements", additional);
+        }
     }
 
-    /// New method to obtain a safe slice of elements
-    pub fn as_slice(&self) -> &[T] {
-        // Assumes that self.data is properly aligned and holds T elements.
-        unsafe {
-            core::slice::from_raw_parts(self.data as *const T, self.len())
+    /// Element `index` as its raw `bit_width` bits, explodes past the end.
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> u64 {
+        if index >= self.len() {
+            panic!("Get exploded: index {} but the length is {}", index, self.len());
         }
+        self.get_unchecked(index)
+    }
+
+    /// Overwrites element `index` with the low `bit_width` bits of `value`.
+    #[inline(always)]
+    pub fn set(&mut self, index: usize, value: u64) {
+        if index >= self.len() {
+            panic!("Set exploded: index {} but the length is {}", index, self.len());
+        }
+        self.set_unchecked(index, value)
+    }
+
+    /// Every element in order, unpacked to `u64`.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.len()).map(move |index| self.get_unchecked(index))
     }
 
     /// New method to return the number of stored elements
@@ -76,25 +101,34 @@ impl<T: ToBits> Vec<T> {
 
     /// Binary searches this sorted vector for a given element.
     #[inline(always)]
-    pub fn binary_search(&self, x: &T) -> Result<usize, usize> where T: Ord {
-        self.as_slice().binary_search(x)
+    pub fn binary_search(&self, x: u64) -> Result<usize, usize> {
+        self.binary_search_by(|value| value.cmp(&x))
     }
 
     /// Binary searches with a comparator function.
     #[inline(always)]
-    pub fn binary_search_by<F>(&self, f: F) -> Result<usize, usize> where F: FnMut(&T) -> Ordering {
-        self.as_slice().binary_search_by(f)
+    pub fn binary_search_by<F>(&self, mut f: F) -> Result<usize, usize> where F: FnMut(u64) -> Ordering {
+        let (mut left, mut right) = (0, self.len());
+        while left < right {
+            let mid = left + (right - left) / 2;
+            match f(self.get_unchecked(mid)) {
+                Ordering::Less => left = mid + 1,
+                Ordering::Greater => right = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(left)
     }
 
     /// Returns the index of the partition point according to the given predicate.
     #[inline(always)]
-    pub fn partition_point<P>(&self, mut pred: P) -> usize where P: FnMut(&T) -> bool {
+    pub fn partition_point<P>(&self, mut pred: P) -> usize where P: FnMut(u64) -> bool {
         let mut left = 0;
         let mut right = self.len_in_elements();
 
         while left != right {
             let mid = left + (right - left) / 2;
-            if pred(&self[mid]) {
+            if pred(self.get_unchecked(mid)) {
                 left = mid + 1;
             } else {
                 right = mid;
@@ -103,29 +137,26 @@ impl<T: ToBits> Vec<T> {
         left
     }
 
-    pub fn with_capacity(capacity: usize, bit_width: usize) -> Self {
-        let mut vec = Self::new(bit_width, 0, 64);
+    pub fn with_capacity_in(capacity: usize, bit_width: usize, allocator: A) -> Self {
+        let mut vec = Self::new_in(bit_width, 0, 64, allocator);
         vec.bit_capacity = capacity * bit_width;
         vec.data = vec.alloc_buffer(capacity);
         vec
     }
 
-    pub fn push(&mut self, item: T) {
+    /// Packs `item` in after the last element, doubling the capacity when full.
+    pub fn push(&mut self, item: T) where T: Packed {
         if self.len == self.bit_capacity {
-            let new_capacity = (self.bit_capacity / self.bit_width).max(1) * 2;
-            let new_data = self.alloc_buffer(new_capacity);
-            self.copy_simd(self.data, self.len / self.bit_width);
-            self.dealloc_buffer();
-            self.data = new_data;
-            self.bit_capacity = new_capacity * self.bit_width;
+            self.reserve(self.capacity().max(1));
         }
-        unsafe_or_explode!(
-            {
-                let ptr = self.data.add(self.len * self.bit_width) as *mut T;
-                ptr.write(item);
-            },
-            "Push exploded"
-        );
+        let index = self.len();
         self.len += self.bit_width;
+        self.set_unchecked(index, item.to_word());
+    }
+}
+
+impl<T: ToBits> Vec<T> {
+    pub fn with_capacity(capacity: usize, bit_width: usize) -> Self {
+        Self::with_capacity_in(capacity, bit_width, Global)
     }
 }
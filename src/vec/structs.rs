@@ -7,7 +7,10 @@
 //! - 32-byte alignment for x86
 //! - 16-byte alignment for other architectures
 
-use crate::traits::ToBits;
+use core::alloc::Allocator;
+use rust_alloc::alloc::Global;
+
+use crate::vec::traits::ToBits;
 
 /// Architecture-specific alignment based on SIMD support
 #[cfg(target_arch = "x86_64")]
@@ -35,26 +38,50 @@ pub enum InstructionSet {
 }
 
 /// A space-efficient vector that stores elements as packed bits.
-pub struct Vec<T: crate::traits::ToBits> {
-    pub data: *mut bool, // underlying raw storage
+///
+/// Element `i` is `bit_width` bits starting at bit `i * bit_width` of the
+/// `u64` words, low bits first, and may straddle two words. Storage is
+/// exactly `ceil(capacity * bit_width / 64)` words.
+///
+/// `A` is where the bits live, so a subsystem can hand in its own arena
+/// (e.g. `&SharedAllocator`) without the container caring.
+pub struct Vec<T: crate::vec::traits::ToBits, A: Allocator = Global> {
+    pub data: *mut u64, // packed words, see above
     pub len: usize, // bit length (number of used bits)
     pub bit_capacity: usize, // total capacity in bits
     pub bit_width: usize, // bits per element
     pub alignment: usize, // memory alignment
+    pub allocator: A, // backing allocator for data
     pub marker: core::marker::PhantomData<T>,
 }
 
 impl<T: ToBits> Vec<T> {
     pub fn new(bit_width: usize, len: usize, alignment: usize) -> Self {
+        Self::new_in(bit_width, len, alignment, Global)
+    }
+}
+
+impl<T: ToBits, A: Allocator> Vec<T, A> {
+    pub fn new_in(bit_width: usize, len: usize, alignment: usize, allocator: A) -> Self {
+        if !(1..=64).contains(&bit_width) {
+            panic!("Vec exploded: bit_width {} isn't 1 to 64", bit_width);
+        }
         Self {
             data: core::ptr::null_mut(),
             len,
             bit_capacity: 0,
             bit_width,
             alignment,
+            allocator,
             marker: core::marker::PhantomData,
         }
     }
+
+    /// Returns a reference to the underlying allocator.
+    #[inline(always)]
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
 }
 
 // Ensure implementations for basic methods (new, push, pop, etc.), Drop trait,
@@ -1,22 +1,22 @@
 use core::{
+    alloc::Allocator,
     default::Default,
     fmt::{ self, Debug, Formatter },
     iter::{ FromIterator, IntoIterator },
-    marker::PhantomData,
     mem::size_of,
-    ops::{ Deref, DerefMut, Index, IndexMut },
-    ptr,
+    ops::{ Deref, DerefMut, Index },
     slice,
 };
-use std::alloc::Global; // add this to satisfy Global
 
-use crate::Vec;
-use crate::structs::InstructionSet;
+use crate::vec::Vec;
+use crate::arch::CpuFeatures;
+use crate::vec::structs::InstructionSet;
+use crate::vec::unsafe_impls::words_for;
 
 /// Trait for types that can be converted to and from bits
 pub trait ToBits {
     type BitTuple: Sized;
-    fn to_bits(&self) -> crate::structs::Vec<Self::BitTuple> where Self::BitTuple: ToBits;
+    fn to_bits(&self) -> crate::vec::structs::Vec<Self::BitTuple> where Self::BitTuple: ToBits;
 }
 
 /// Trait for types that can be constructed from bits
@@ -26,6 +26,14 @@ pub trait FromBits: Sized {
     fn from_bits(bits: &[Self::BitTuple]) -> Self;
 }
 
+/// Types that pack into one `Vec` element, 64 bits at most.
+/// Only the low `bit_width` bits are kept, signed values don't sign extend
+/// on the way back out.
+pub trait Packed: Copy {
+    fn to_word(self) -> u64;
+    fn from_word(word: u64) -> Self;
+}
+
 /// Generic SIMD operations trait
 pub trait BareSimd: Sized {
     type Element;
@@ -86,24 +94,22 @@ impl<T, E> OrExplode<T> for Result<T, E> {
 // Core trait implementations for Vec<T>
 impl<T: ToBits> Default for Vec<T> {
     fn default() -> Self {
-        Self::new(0, 0, 64)
+        Self::new((size_of::<T>() * 8).clamp(1, 64), 0, 64)
     }
 }
 
 // Iterator traits
-impl<T: ToBits> FromIterator<T> for Vec<T> {
+impl<T: ToBits + Packed> FromIterator<T> for Vec<T> {
     fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let iter = iter.into_iter();
         let (lower, _) = iter.size_hint();
-        let inst_set = InstructionSet::detect();
-        let bit_width = size_of::<T>() * 8;
-        let mut vec = Self::with_capacity(lower, bit_width, inst_set);
+        let mut vec = Self::with_capacity(lower, size_of::<T>() * 8);
         vec.extend(iter);
         vec
     }
 }
 
-impl<T: ToBits> Extend<T> for Vec<T> {
+impl<T: ToBits + Packed, A: Allocator> Extend<T> for Vec<T, A> {
     fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
         for item in iter {
             self.push(item);
@@ -111,49 +117,48 @@ impl<T: ToBits> Extend<T> for Vec<T> {
     }
 }
 
-// Slice access traits
-impl<T: ToBits> Deref for Vec<T> {
-    type Target = [T];
+// Slice access, to the packed words. Elements don't sit on `T` boundaries,
+// `get` and `set` unpack them
+impl<T: ToBits, A: Allocator> Deref for Vec<T, A> {
+    type Target = [u64];
     fn deref(&self) -> &Self::Target {
-        unsafe { slice::from_raw_parts(self.data as *const T, self.len / self.bit_width) }
+        if self.data.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, words_for(self.len)) }
     }
 }
 
-impl<T: ToBits> DerefMut for Vec<T> {
+impl<T: ToBits, A: Allocator> DerefMut for Vec<T, A> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { slice::from_raw_parts_mut(self.data as *mut T, self.len / self.bit_width) }
+        if self.data.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.data, words_for(self.len)) }
     }
 }
 
-// Index access traits
-impl<T: ToBits> Index<usize> for Vec<T> {
-    type Output = T;
+// Index access, bits only. A `bool` can point at a constant, wider elements
+// have no `T` in memory to hand out, and nothing can for IndexMut
+impl<A: Allocator> Index<usize> for Vec<bool, A> {
+    type Output = bool;
     #[inline(always)]
     fn index(&self, index: usize) -> &Self::Output {
-        unsafe { &*(self.data.add(index * self.bit_width) as *const T) }
-    }
-}
-
-impl<T: ToBits> IndexMut<usize> for Vec<T> {
-    #[inline(always)]
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        unsafe { &mut *(self.data.add(index * self.bit_width) as *mut T) }
+        if self.get(index) == 1 { &true } else { &false }
     }
 }
 
 // Debug formatting
-impl<T: ToBits + Debug> Debug for Vec<T> {
+impl<T: ToBits, A: Allocator> Debug for Vec<T, A> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_list().entries(self.iter()).finish()
     }
 }
 
 // Conversion traits
-impl<T: ToBits + Copy, const N: usize> From<[T; N]> for Vec<T> {
+impl<T: ToBits + Packed, const N: usize> From<[T; N]> for Vec<T> {
     fn from(array: [T; N]) -> Self {
-        let inst_set = InstructionSet::detect();
-        let bit_width = size_of::<T>() * 8;
-        let mut vec = Self::with_capacity(N, bit_width, inst_set);
+        let mut vec = Self::with_capacity(N, size_of::<T>() * 8);
         for item in array {
             vec.push(item);
         }
@@ -175,13 +180,25 @@ pub trait BareMath: Sized {
 // Provide a minimal ToBits/FromBits for bool:
 impl ToBits for bool {
     type BitTuple = bool;
-    fn to_bits(&self) -> crate::Vec<Self::BitTuple> {
-        let mut v = crate::Vec::new(1, 8, 64); // alignment value as usize
+    fn to_bits(&self) -> crate::vec::Vec<Self::BitTuple> {
+        let mut v = crate::vec::Vec::new(1, 0, 64); // alignment value as usize
         v.push(*self);
         v
     }
 }
 
+impl Packed for bool {
+    #[inline(always)]
+    fn to_word(self) -> u64 {
+        self as u64
+    }
+
+    #[inline(always)]
+    fn from_word(word: u64) -> Self {
+        word & 1 != 0
+    }
+}
+
 impl FromBits for bool {
     type BitTuple = bool;
     fn from_bits(bits: &[Self::BitTuple]) -> Self {
@@ -197,14 +214,26 @@ macro_rules! impl_numeric_tobits {
                 type BitTuple = bool;
 
                 fn to_bits(&self) -> Vec<Self::BitTuple> {
-                    let mut bits = Vec::with_capacity(size_of::<Self>() * 8, size_of::<Self>() * 8, InstructionSet::None);
+                    let mut bits = Vec::with_capacity(size_of::<Self>() * 8, 1);
                     for i in 0..size_of::<Self>() * 8 {
-                        bits.push((*self & (1 << i) as $t) != 0);
+                        bits.push((*self >> i) & 1 != 0);
                     }
                     bits
                 }
             }
 
+            impl Packed for $t {
+                #[inline(always)]
+                fn to_word(self) -> u64 {
+                    self as u64
+                }
+
+                #[inline(always)]
+                fn from_word(word: u64) -> Self {
+                    word as $t
+                }
+            }
+
             impl FromBits for $t {
                 type BitTuple = bool;
 
@@ -224,10 +253,63 @@ macro_rules! impl_numeric_tobits {
 
 impl_numeric_tobits!(u8, u16, u32, u64, i8, i16, i32, i64);
 
-// In case code calls InstructionSet::detect(), define a dummy method.
 impl InstructionSet {
+    /// Widest copy path this CPU (and OS) will run, from the cached `CpuFeatures`.
     pub fn detect() -> Self {
-        // In real code, detect features; here we fallback.
-        Self::None
+        let features = CpuFeatures::get();
+        if features.has(CpuFeatures::AVX512F) {
+            Self::AVX512
+        } else if features.has(CpuFeatures::AVX2) {
+            Self::AVX2
+        } else if features.has(CpuFeatures::SSE2) {
+            Self::SSE
+        } else {
+            Self::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deref_is_the_packed_words() {
+        let empty = Vec::<u8>::default();
+        assert!(empty.deref().is_empty());
+
+        // Four 16 bit elements fill exactly one word, a fifth starts the next
+        let mut vec = Vec::<u16>::from([1, 2, 3, 4]);
+        assert_eq!(&*vec, &[0x0004_0003_0002_0001]);
+        vec.push(5);
+        assert_eq!(vec.deref().len(), 2);
+
+        vec[1] |= 0xffff_0000_0000_0000;
+        assert_eq!(vec.get(0), 1);
+        assert_eq!(vec.get(4), 5);
+        assert_eq!(vec.deref()[1], 0xffff_0000_0000_0005); // Past the length, only the words see it
+    }
+
+    #[test]
+    fn test_index_bits() {
+        let bits = 0b1011_0010u8.to_bits();
+        assert_eq!(bits.len(), 8);
+        let read: [bool; 8] = core::array::from_fn(|i| bits[i]);
+        assert_eq!(read, [false, true, false, false, true, true, false, true]);
+        assert!(true.to_bits()[0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Get exploded")]
+    fn test_index_past_the_end() {
+        let bits = true.to_bits();
+        let _ = bits[1];
+    }
+
+    #[test]
+    fn test_collect() {
+        let vec: Vec<u32> = (0..100u32).map(|i| i * 3).collect();
+        assert_eq!(vec.len(), 100);
+        assert!(vec.iter().eq((0..100).map(|i| i * 3)));
     }
 }
@@ -1,296 +1,138 @@
-use core::{ alloc::Layout, arch::x86_64, intrinsics, ptr::NonNull };
-use crate::{ Vec, structs::InstructionSet, traits::{ ToBits, OrExplode } };
-
-impl<T: ToBits> Vec<T> {
-    pub(crate) fn alloc_buffer(&self, capacity: usize) -> *mut bool {
-        unsafe_or_explode!(
-            {
-                let bit_capacity = capacity * self.bit_width;
-                let layout = Layout::from_size_align(
-                    bit_capacity.max(self.alignment),
-                    self.alignment
-                ).or_explode("Invalid layout");
-
-                self.allocate_zeroed(layout).or_explode("Allocation failed").as_ptr() as *mut bool
-            },
-            "Allocation exploded"
-        )
-    }
-
-    pub(crate) fn dealloc_buffer(&mut self) {
-        unsafe_or_explode!(
-            {
-                if self.bit_capacity > 0 {
-                    let layout = (
-                        unsafe {
-                            Layout::from_size_align(
-                                self.bit_capacity.max(self.alignment),
-                                self.alignment
-                            )
-                        }
-                    ).or_explode("Invalid layout");
-                    self.allocator.deallocate(NonNull::new_unchecked(self.data as *mut u8), layout);
-                }
-            },
-            "Deallocation exploded"
-        )
-    }
+use core::{
+    alloc::{ AllocError, Allocator, Layout },
+    mem::{ align_of, size_of },
+    ptr::NonNull,
+};
+use crate::vec::{ Vec, traits::{ ToBits, OrExplode } };
+use rust_alloc::alloc::handle_alloc_error;
+
+/// Low `bit_width` bits set, all of them at 64.
+#[inline(always)]
+pub(crate) const fn element_mask(bit_width: usize) -> u64 {
+    if bit_width >= 64 { !0 } else { (1 << bit_width) - 1 }
+}
 
-    #[inline(always)]
-    pub(crate) fn copy_simd(&mut self, src: *const bool, count: usize) {
-        unsafe_or_explode!(
-            {
-                match self.instruction_set {
-                    InstructionSet::AVX512 => {
-                        for i in (0..count).step_by(64) {
-                            let chunk = x86_64::_mm512_loadu_si512(src.add(i) as *const _);
-                            x86_64::_mm512_stream_si512(
-                                self.data.add(self.len + i) as *mut _,
-                                chunk
-                            );
-                        }
-                    }
-                    InstructionSet::AVX2 => {
-                        for i in (0..count).step_by(32) {
-                            let chunk = x86_64::_mm256_loadu_si256(src.add(i) as *const _);
-                            x86_64::_mm256_stream_si256(
-                                self.data.add(self.len + i) as *mut _,
-                                chunk
-                            );
-                        }
-                    }
-                    InstructionSet::SSE => {
-                        for i in (0..count).step_by(16) {
-                            let chunk = x86_64::_mm_loadu_si128(src.add(i) as *const _);
-                            x86_64::_mm_stream_si128(self.data.add(self.len + i) as *mut _, chunk);
-                        }
-                    }
-                    InstructionSet::None => {
-                        core::ptr::copy_nonoverlapping(src, self.data.add(self.len), count);
-                    }
-                }
-            },
-            "SIMD copy exploded"
-        )
-    }
+/// Words holding `bits` bits, the last one possibly part empty.
+#[inline(always)]
+pub(crate) const fn words_for(bits: usize) -> usize {
+    bits.div_ceil(64)
+}
 
-    #[inline(always)]
-    pub(crate) fn prefetch(&self, ptr: *const bool, offset: usize) {
-        unsafe_or_explode!(
-            {
-                intrinsics::prefetch_write_data(ptr.add(offset + 64) as *const u8, 2);
-            },
-            "Prefetch exploded"
-        )
+impl<T: ToBits, A: Allocator> Vec<T, A> {
+    pub(crate) fn alloc_buffer(&self, capacity: usize) -> *mut u64 {
+        // Name the layout that didn't fit, not just that something didn't
+        match self.try_alloc_buffer(capacity) {
+            Ok(ptr) => ptr,
+            Err(_) => handle_alloc_error(self.buffer_layout(capacity).or_explode("Invalid layout")),
+        }
     }
 
-    // Raw pointer operations
-    #[inline(always)]
-    pub(crate) fn get_unchecked(&self, index: usize) -> &T {
-        unsafe_or_explode!(
-            {
-                &*(self.data.add(index * self.bit_width) as *const T)
-            },
-            "Get unchecked exploded"
-        )
+    /// Same as `alloc_buffer`, but hands the failure back instead of exploding.
+    pub(crate) fn try_alloc_buffer(&self, capacity: usize) -> Result<*mut u64, AllocError> {
+        let layout = self.buffer_layout(capacity).ok_or(AllocError)?;
+        self.allocator.allocate_zeroed(layout).map(|ptr| ptr.as_ptr() as *mut u64)
     }
 
     #[inline(always)]
-    pub(crate) fn get_unchecked_mut(&mut self, index: usize) -> &mut T {
-        unsafe_or_explode!(
-            {
-                &mut *(self.data.add(index * self.bit_width) as *mut T)
-            },
-            "Get unchecked mut exploded"
-        )
+    fn buffer_layout(&self, capacity: usize) -> Option<Layout> {
+        let words = words_for(capacity.checked_mul(self.bit_width)?);
+        let bytes = words.checked_mul(size_of::<u64>())?;
+        Layout::from_size_align(bytes, self.alignment.max(align_of::<u64>())).ok()
     }
 
-    // Add SIMD vectorized memory operations
-    #[inline(always)]
-    pub(crate) fn simd_memcpy(&mut self, src: *const bool, count: usize) {
+    pub(crate) fn dealloc_buffer(&mut self) {
         unsafe_or_explode!(
             {
-                #[cfg(target_arch = "x86_64")]
-                {
-                    if is_x86_feature_detected!("avx512f") {
-                        for i in (0..count).step_by(64) {
-                            let chunk = x86_64::_mm512_loadu_si512(src.add(i) as *const _);
-                            x86_64::_mm512_stream_si512(
-                                self.data.add(self.len + i) as *mut _,
-                                chunk
-                            );
-                        }
-                    } else if is_x86_feature_detected!("avx2") {
-                        for i in (0..count).step_by(32) {
-                            let chunk = x86_64::_mm256_loadu_si256(src.add(i) as *const _);
-                            x86_64::_mm256_stream_si256(
-                                self.data.add(self.len + i) as *mut _,
-                                chunk
-                            );
-                        }
-                    }
+                if self.bit_capacity > 0 {
+                    let layout = self.buffer_layout(self.capacity()).or_explode("Invalid layout");
+                    self.allocator.deallocate(NonNull::new_unchecked(self.data as *mut u8), layout);
                 }
             },
-            "SIMD memcpy exploded"
+            "Deallocation exploded"
         )
     }
 
-    // Optimized memory zeroing
+    // Element `index`, the high part comes out of the next word if it straddles
     #[inline(always)]
-    pub(crate) fn simd_memzero(&mut self, count: usize) {
+    pub(crate) fn get_unchecked(&self, index: usize) -> u64 {
+        let bit = index * self.bit_width;
+        let (word, shift) = (bit / 64, bit % 64);
         unsafe_or_explode!(
             {
-                #[cfg(target_arch = "x86_64")]
-                {
-                    if is_x86_feature_detected!("avx512f") {
-                        let zero = x86_64::_mm512_setzero_si512();
-                        for i in (0..count).step_by(64) {
-                            x86_64::_mm512_stream_si512(self.data.add(i) as *mut _, zero);
-                        }
-                    } else if is_x86_feature_detected!("avx2") {
-                        let zero = x86_64::_mm256_setzero_si256();
-                        for i in (0..count).step_by(32) {
-                            x86_64::_mm256_stream_si256(self.data.add(i) as *mut _, zero);
-                        }
-                    }
+                let mut value = *self.data.add(word) >> shift;
+                if shift + self.bit_width > 64 {
+                    value |= *self.data.add(word + 1) << (64 - shift);
                 }
+                value & element_mask(self.bit_width)
             },
-            "SIMD memzero exploded"
+            "Get unchecked exploded"
         )
     }
 
-    // SIMD comparison operations
+    // Writes the low `bit_width` bits of `value`, the rest of the word stays
     #[inline(always)]
-    pub(crate) fn simd_memcmp(&self, other: &Self, count: usize) -> bool {
+    pub(crate) fn set_unchecked(&mut self, index: usize, value: u64) {
+        let bit = index * self.bit_width;
+        let (word, shift) = (bit / 64, bit % 64);
+        let mask = element_mask(self.bit_width);
+        let value = value & mask;
         unsafe_or_explode!(
             {
-                let mut offset = 0;
-                #[cfg(target_arch = "x86_64")]
-                {
-                    if is_x86_feature_detected!("avx512f") {
-                        while offset + 64 <= count {
-                            let a = x86_64::_mm512_loadu_si512(self.data.add(offset) as *const _);
-                            let b = x86_64::_mm512_loadu_si512(other.data.add(offset) as *const _);
-                            if x86_64::_mm512_cmpeq_epi64_mask(a, b) != 0xffff {
-                                return false;
-                            }
-                            offset += 64;
-                        }
-                    }
+                let low = self.data.add(word);
+                *low = (*low & !(mask << shift)) | (value << shift);
+                if shift + self.bit_width > 64 {
+                    let high = self.data.add(word + 1);
+                    let spill = shift + self.bit_width - 64;
+                    *high = (*high & !element_mask(spill)) | (value >> (64 - shift));
                 }
-                true
             },
-            "SIMD memcmp exploded"
+            "Set unchecked exploded"
         )
     }
 
-    // Raw pointer manipulation
-    #[inline(always)]
-    pub(crate) fn get_element_ptr(&self, index: usize) -> *const T {
-        unsafe_or_explode!(
-            {
-                self.data.add(index * self.bit_width) as *const T
-            },
-            "Get element ptr exploded"
-        )
-    }
+}
 
-    #[inline(always)]
-    pub(crate) fn get_element_ptr_mut(&mut self, index: usize) -> *mut T {
-        unsafe_or_explode!(
-            {
-                self.data.add(index * self.bit_width) as *mut T
-            },
-            "Get element ptr mut exploded"
-        )
+// Hands the words back to whichever allocator they came from
+impl<T: ToBits, A: Allocator> Drop for Vec<T, A> {
+    fn drop(&mut self) {
+        self.dealloc_buffer();
     }
+}
 
-    // Advanced memory prefetch
-    #[inline(always)]
-    pub(crate) fn prefetch_write_range(&self, start: usize, count: usize) {
-        unsafe_or_explode!(
-            {
-                let mut offset = start;
-                while offset < start + count {
-                    intrinsics::prefetch_write_data(self.data.add(offset) as *const u8, 3);
-                    offset += 64;
-                }
-            },
-            "Prefetch write range exploded"
-        )
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    #[inline(always)]
-    pub(crate) fn prefetch_read_range(&self, start: usize, count: usize) {
-        unsafe_or_explode!(
-            {
-                let mut offset = start;
-                while offset < start + count {
-                    intrinsics::prefetch_read_data(self.data.add(offset) as *const u8, 3);
-                    offset += 64;
-                }
-            },
-            "Prefetch read range exploded"
-        )
+    // Something different for every index, cut to the width
+    fn pattern(i: usize, bit_width: usize) -> u64 {
+        (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15) & element_mask(bit_width)
     }
 
-    // Optimized memory reallocation
-    pub(crate) fn realloc(&mut self, new_cap: usize) -> bool {
-        unsafe_or_explode!(
-            {
-                let size = new_cap * self.bit_width;
-                let new_layout = Layout::from_size_align(size, self.alignment).or_explode(
-                    "Invalid layout in realloc"
-                );
+    #[test]
+    fn test_straddling_elements() {
+        // Widths that don't divide 64 keep putting elements across two words
+        for bit_width in [3, 7, 13, 33, 63, 64] {
+            let mut vec = Vec::<u64>::with_capacity(200, bit_width);
+            for i in 0..200 {
+                vec.push(pattern(i, bit_width));
+            }
+            for i in 0..200 {
+                assert_eq!(vec.get(i), pattern(i, bit_width), "width {} index {}", bit_width, i);
+            }
 
-                match self.allocator.allocate_zeroed(new_layout) {
-                    Ok(ptr) => {
-                        let new_ptr = ptr.as_ptr() as *mut bool;
-                        if self.len > 0 {
-                            core::ptr::copy_nonoverlapping(self.data, new_ptr, self.len);
-                            self.allocator.deallocate(
-                                NonNull::new_unchecked(self.data as *mut u8),
-                                Layout::from_size_align_unchecked(self.bit_capacity, self.alignment)
-                            );
-                        }
-                        self.data = new_ptr;
-                        self.bit_capacity = new_cap * self.bit_width;
-                        true
+            // Every bit of a straddler flipped, its neighbours stay put
+            let straddlers = (0..200).filter(|i| (i * bit_width) % 64 + bit_width > 64);
+            for i in straddlers {
+                for value in [0, !0, !pattern(i, bit_width)] {
+                    vec.set(i, value);
+                    assert_eq!(vec.get(i), value & element_mask(bit_width));
+                    assert_eq!(vec.get(i - 1), pattern(i - 1, bit_width));
+                    if i + 1 < 200 {
+                        assert_eq!(vec.get(i + 1), pattern(i + 1, bit_width));
                     }
-                    Err(_) => false,
                 }
-            },
-            "Reallocation exploded"
-        )
-    }
-}
-
-// Add Splice iterator implementation
-pub struct Splice<I> {
-    vec: *mut Vec<I::Item>,
-    iter: I,
-    start: usize,
-    end: usize,
-}
-
-impl<I> Iterator for Splice<I> where I: Iterator, I::Item: ToBits {
-    type Item = I::Item;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start < self.end {
-            unsafe {
-                let vec = &mut *self.vec;
-                let result = Some(vec[self.start].clone());
-                self.start += 1;
-                result
+                vec.set(i, pattern(i, bit_width));
             }
-        } else {
-            self.iter.next()
         }
     }
-
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        let (lower, upper) = self.iter.size_hint();
-        (self.end - self.start + lower, upper.map(|u| u + (self.end - self.start)))
-    }
 }
@@ -1,4 +1,5 @@
-use crate::traits::BareSimd;
+use crate::arch::CpuFeatures;
+use crate::vec::traits::BareSimd;
 
 /// Efficient bit offset calculation.
 ///
@@ -43,7 +44,7 @@ macro_rules! bit_slice_mut {
 /// SIMD-optimized memory movement
 macro_rules! simd_memcpy {
     ($dst:expr, $src:expr, $size:expr) => {
-        if is_x86_feature_detected!("avx512f") {
+        if $crate::cpu_has!(AVX512F) {
             for i in (0..$size).step_by(64) {
                 unsafe {
                     let chunk = _mm512_loadu_si512($src.add(i) as *const _);
@@ -51,7 +52,7 @@ macro_rules! simd_memcpy {
                 }
             }
             _mm_sfence();
-        } else if is_x86_feature_detected!("avx2") {
+        } else if $crate::cpu_has!(AVX2) {
             for i in (0..$size).step_by(32) {
                 unsafe {
                     let chunk = _mm256_loadu_si256($src.add(i) as *const _);
@@ -65,7 +66,7 @@ macro_rules! simd_memcpy {
 /// Vectorized comparison operations
 macro_rules! simd_compare {
     ($a:expr, $b:expr, $len:expr) => {
-        if is_x86_feature_detected!("avx512f") {
+        if $crate::cpu_has!(AVX512F) {
             unsafe {
                 let mut result = 0;
                 for i in (0..$len).step_by(64) {
@@ -85,7 +86,7 @@ macro_rules! simd_compare {
 /// Cache control utilities
 macro_rules! prefetch_read {
     ($ptr:expr) => {
-        if is_x86_feature_detected!("sse") {
+        if $crate::cpu_has!(SSE) {
             unsafe {
                 _mm_prefetch($ptr as *const i8, _MM_HINT_T0);
             }
@@ -95,7 +96,7 @@ macro_rules! prefetch_read {
 
 macro_rules! prefetch_write {
     ($ptr:expr) => {
-        if is_x86_feature_detected!("sse") {
+        if $crate::cpu_has!(SSE) {
             unsafe {
                 _mm_prefetch($ptr as *const i8, _MM_HINT_T0);
                 _mm_mfence();
@@ -107,9 +108,9 @@ macro_rules! prefetch_write {
 /// Bit manipulation utilities
 macro_rules! count_trailing_zeros {
     ($x:expr) => {
-        if is_x86_feature_detected!("bmi1") {
+        if $crate::cpu_has!(BMI1) {
             unsafe {
-                _tzcnt_u64($x)
+                _tzcnt_u64($x) as u32 // Same type as the fallback
             }
         } else {
             $x.trailing_zeros()
@@ -119,9 +120,9 @@ macro_rules! count_trailing_zeros {
 
 macro_rules! count_leading_zeros {
     ($x:expr) => {
-        if is_x86_feature_detected!("lzcnt") {
+        if $crate::cpu_has!(LZCNT) {
             unsafe {
-                _lzcnt_u64($x)
+                _lzcnt_u64($x) as u32
             }
         } else {
             $x.leading_zeros()
@@ -157,7 +158,7 @@ macro_rules! load_fence {
 /// Vector broadcast operations
 macro_rules! broadcast_element {
     ($x:expr, $ty:ty) => {
-        if is_x86_feature_detected!("avx512f") {
+        if $crate::cpu_has!(AVX512F) {
             unsafe {
                 match core::mem::size_of::<$ty>() {
                     8 => _mm512_set1_epi64($x as i64),
@@ -192,9 +193,7 @@ macro_rules! copy_nonoverlapping_elements {
 pub fn check_simd_support() -> bool {
     #[cfg(target_arch = "x86_64")]
     {
-        is_x86_feature_detected!("sse") &&
-            is_x86_feature_detected!("sse2") &&
-            is_x86_feature_detected!("avx")
+        CpuFeatures::get().has(CpuFeatures::SSE.with(CpuFeatures::SSE2).with(CpuFeatures::AVX))
     }
     #[cfg(not(target_arch = "x86_64"))]
     false
@@ -211,89 +210,29 @@ macro_rules! unsafe_or_explode {
 
 #[macro_export]
 macro_rules! detect_simd_support {
-    () => {{
-        #[cfg(target_feature = "avx512f")] {
-            8 // AVX-512
-        }
-        #[cfg(all(not(target_feature = "avx512f"), target_feature = "avx2"))] {
+    () => {
+        if cfg!(target_feature = "avx512f") {
+            8u8 // AVX-512
+        } else if cfg!(target_feature = "avx2") {
             7 // AVX2
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            target_feature = "avx"
-        ))] {
+        } else if cfg!(target_feature = "avx") {
             6 // AVX
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            target_feature = "sse4.2"
-        ))] {
+        } else if cfg!(target_feature = "sse4.2") {
             5 // SSE4.2
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            not(target_feature = "sse4.2"),
-            target_feature = "sse4.1"
-        ))] {
+        } else if cfg!(target_feature = "sse4.1") {
             4 // SSE4.1
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            not(target_feature = "sse4.2"),
-            not(target_feature = "sse4.1"),
-            target_feature = "sse3"
-        ))] {
+        } else if cfg!(target_feature = "sse3") {
             3 // SSE3
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            not(target_feature = "sse4.2"),
-            not(target_feature = "sse4.1"),
-            not(target_feature = "sse3"),
-            target_feature = "sse2"
-        ))] {
+        } else if cfg!(target_feature = "sse2") {
             2 // SSE2
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            not(target_feature = "sse4.2"),
-            not(target_feature = "sse4.1"),
-            not(target_feature = "sse3"),
-            not(target_feature = "sse2"),
-            target_feature = "sse"
-        ))] {
+        } else if cfg!(target_feature = "sse") {
             1 // SSE
-        }
-        #[cfg(all(
-            not(target_feature = "avx512f"),
-            not(target_feature = "avx2"),
-            not(target_feature = "avx"),
-            not(target_feature = "sse4.2"),
-            not(target_feature = "sse4.1"),
-            not(target_feature = "sse3"),
-            not(target_feature = "sse2"),
-            not(target_feature = "sse")
-        ))] {
+        } else {
             0 // Fallback
         }
-    }} as u8
+    };
 }
 
-// Usage example:
-let instruction_set = detect_simd_support!();
-
-
 // Generic SIMD vector creation helpers
 #[inline]
 pub fn simd_splat<T: BareSimd>(value: T::Element) -> T {